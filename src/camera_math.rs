@@ -23,6 +23,10 @@ impl Camera {
 	let y_offset = f_sh*self.rotation.z.sin();
 	self.location-Vector3{x: x_offset, y: y_offset, z:z_offset}
     }
+    pub fn pixel_spread(&self) -> f64 {
+	// how much a pixel's footprint widens per unit distance along its ray
+	(self.hx/(self.resolution.x as f64))/self.focal_length
+    }
     pub fn pixel_to_world(&self, x: usize, y: usize) -> Point3<f64> {
 	// first, calculate the point when rotations all equal 0
 	let x_offset = self.hx*((x as f64 + 0.5)/(self.resolution.x as f64)-0.5);
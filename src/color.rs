@@ -9,6 +9,9 @@ pub struct Color {
 }
 
 impl Color {
+    pub const fn new(red: f64, green: f64, blue: f64) -> Self {
+	Color{red: red, green: green, blue: blue}
+    }
     pub fn clamp(&mut self){
 	if self.red < 0.0 {
 	    self.red = 0.0;
@@ -59,6 +62,12 @@ impl Color {
 	 Color::p_linear_to_srgb(self.green),
 	 Color::p_linear_to_srgb(self.blue))
     }
+    pub fn channels(&self) -> (f64, f64, f64) {
+	(self.red, self.green, self.blue)
+    }
+    pub fn luminance(&self) -> f64 { // Rec. 709 relative luminance
+	0.2126*self.red + 0.7152*self.green + 0.0722*self.blue
+    }
     pub fn new_from_srgb(red: u8, green: u8, blue: u8) -> Self {
 	Color{red: Color::p_srgb_to_linear(red),
 	      green: Color::p_srgb_to_linear(green),
@@ -67,6 +76,17 @@ impl Color {
     pub fn new_from_linear(red: u8, green: u8, blue: u8) -> Self {
 	Color{red: (red as f64)/255.0, blue: (blue as f64)/255.0, green: (green as f64)/255.0}
     }
+    pub fn new_from_temperature(kelvin: f64) -> Self {
+	// black body colour, fitted curve (Tanner Helland) good for 1000K to 40000K, 6600K is white
+	let t = kelvin.max(1000.0).min(40000.0)/100.0;
+	let red = if t <= 66.0 {255.0} else {329.698727446*(t-60.0).powf(-0.1332047592)};
+	let green = if t <= 66.0 {99.4708025861*t.ln()-161.1195681661}
+	            else {288.1221695283*(t-60.0).powf(-0.0755148492)};
+	let blue = if t >= 66.0 {255.0} else if t <= 19.0 {0.0}
+	           else {138.5177312231*(t-10.0).ln()-305.0447927307};
+	let to_u8 = |x: f64| x.max(0.0).min(255.0) as u8;
+	Color::new_from_srgb(to_u8(red), to_u8(green), to_u8(blue))
+    }
 }
 
 impl AddAssign<Color> for Color {
@@ -0,0 +1,46 @@
+use crate::color::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Conductor { // complex index of refraction n + ik, per rgb channel
+    pub n: (f64, f64, f64),
+    pub k: (f64, f64, f64)
+}
+
+impl Conductor {
+    pub const fn new(n: (f64, f64, f64), k: (f64, f64, f64)) -> Self {
+	Conductor{n: n, k: k}
+    }
+    fn channel_fresnel(cos_i: f64, n: f64, k: f64) -> f64 {
+	// exact unpolarized fresnel reflectance for a conductor seen from air
+	let cos2 = cos_i*cos_i;
+	let sin2 = 1.0-cos2;
+	let t0 = n*n - k*k - sin2;
+	let a2b2 = (t0*t0 + 4.0*n*n*k*k).sqrt();
+	let a = ((a2b2+t0)*0.5).max(0.0).sqrt();
+	let t1 = a2b2 + cos2;
+	let t2 = 2.0*a*cos_i;
+	let r_s = (t1-t2)/(t1+t2);
+	let t3 = cos2*a2b2 + sin2*sin2;
+	let t4 = t2*sin2;
+	let r_p = r_s*(t3-t4)/(t3+t4);
+	(r_s+r_p)/2.0
+    }
+    pub fn fresnel(&self, cos_i: f64) -> Color {
+	let cos_i = cos_i.abs().min(1.0);
+	Color::new(Conductor::channel_fresnel(cos_i, self.n.0, self.k.0),
+		   Conductor::channel_fresnel(cos_i, self.n.1, self.k.1),
+		   Conductor::channel_fresnel(cos_i, self.n.2, self.k.2))
+    }
+}
+
+
+pub mod presets { // measured values sampled near 650nm, 550nm and 450nm
+    use crate::conductor::Conductor;
+    pub const GOLD:      Conductor = Conductor::new((0.143, 0.374, 1.442), (3.983, 2.385, 1.603));
+    pub const SILVER:    Conductor = Conductor::new((0.155, 0.117, 0.138), (4.828, 3.122, 2.147));
+    pub const COPPER:    Conductor = Conductor::new((0.200, 0.924, 1.102), (3.912, 2.452, 2.142));
+    pub const ALUMINIUM: Conductor = Conductor::new((1.657, 0.880, 0.521), (9.224, 6.270, 4.837));
+    pub const IRON:      Conductor = Conductor::new((2.911, 2.950, 2.585), (3.089, 2.932, 2.767));
+    pub const TITANIUM:  Conductor = Conductor::new((2.745, 2.541, 2.267), (3.814, 3.435, 3.039));
+    pub const CHROMIUM:  Conductor = Conductor::new((3.180, 3.180, 2.010), (3.300, 3.330, 3.040));
+}
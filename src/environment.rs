@@ -0,0 +1,52 @@
+use cgmath::Vector3;
+use crate::color::*;
+use crate::texture::*;
+
+pub enum Background { // what rays see when they leave the scene
+    Color(Color),
+    Gradient{bottom: Color, top: Color},  // blended by the height of the direction
+    Equirectangular(ImageMap),            // latitude-longitude image, z up
+    CubeMap(Vec<ImageMap>)                // faces +x, -x, +y, -y, +z, -z in the OpenGL layout, its +y being our up
+}
+
+impl Background {
+    fn cube_face(direction: Vector3<f64>) -> (usize, f64, f64) {
+	// face index and coordinates in [0, 1] on it, y and z swapped as cube maps are y up
+	let direction = Vector3{x: direction.x, y: direction.z, z: direction.y};
+	let (ax, ay, az) = (direction.x.abs(), direction.y.abs(), direction.z.abs());
+	let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+	    if direction.x > 0.0 {(0, -direction.z, -direction.y, ax)} else {(1, direction.z, -direction.y, ax)}
+	} else if ay >= az {
+	    if direction.y > 0.0 {(2, direction.x, direction.z, ay)} else {(3, direction.x, -direction.z, ay)}
+	} else {
+	    if direction.z > 0.0 {(4, direction.x, -direction.y, az)} else {(5, -direction.x, -direction.y, az)}
+	};
+	(face, (sc/ma+1.0)*0.5, (tc/ma+1.0)*0.5)
+    }
+    pub fn equirectangular_uv(direction: Vector3<f64>) -> (f64, f64) {
+	// u around the z axis, v from the top (0) to the bottom (1)
+	let u = direction.y.atan2(direction.x)/(2.0*std::f64::consts::PI)+0.5;
+	let v = direction.z.max(-1.0).min(1.0).acos()/std::f64::consts::PI;
+	(u, v)
+    }
+    pub fn get_color(&self, direction: Vector3<f64>) -> Color {
+	match *self {
+	    Background::Color(color) => color,
+	    Background::Gradient{bottom, top} => {
+		let t = (direction.z*0.5+0.5).max(0.0).min(1.0);
+		bottom*(1.0-t) + top*t
+	    },
+	    Background::Equirectangular(ref image) => {
+		let (u, v) = Background::equirectangular_uv(direction);
+		image.get_color_uv(u, v)
+	    },
+	    Background::CubeMap(ref faces) => {
+		let (face, u, v) = Background::cube_face(direction);
+		match faces.get(face) {
+		    Some(image) => image.get_color_uv(u, v),
+		    None => consts::BLACK
+		}
+	    }
+	}
+    }
+}
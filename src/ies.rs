@@ -0,0 +1,160 @@
+use std::io::{Error, ErrorKind, Result};
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::sampling;
+
+// IESNA LM-63 photometric profiles, type C only, read into relative intensities
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("IES: {}", message))
+}
+
+fn count(value: f64) -> Result<usize> {
+    // a number of entries, which real files keep well under a few thousand
+    if !value.is_finite() || value < 0.0 || value.fract() != 0.0 || value > 65536.0 {
+	return Err(invalid("bad count"));
+    }
+    Ok(value as usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct IesProfile {
+    vertical: Vec<f64>,   // degrees from the nadir, increasing
+    horizontal: Vec<f64>, // degrees around the nadir, increasing, from 0 or covering 90 to 270
+    candela: Vec<Vec<f64>> // one row of vertical samples per horizontal angle, scaled so the peak is 1
+}
+
+
+impl IesProfile {
+    fn numbers<'a>(words: &mut impl Iterator<Item = &'a str>, count: usize) -> Result<Vec<f64>> {
+	let mut numbers = Vec::new(); // count comes from the file, so grow as the words turn up
+	for _ in 0..count {
+	    let word = words.next().ok_or_else(|| invalid("truncated data"))?;
+	    numbers.push(word.parse::<f64>().map_err(|_| invalid("malformed number"))?);
+	}
+	Ok(numbers)
+    }
+    pub fn read(text: &str) -> Result<IesProfile> {
+	// keyword lines come first, then TILT=, then whitespace separated numbers
+	let tilt_start = text.find("TILT=").ok_or_else(|| invalid("missing TILT line"))?;
+	let rest = &text[tilt_start..];
+	let line_end = rest.find('\n').unwrap_or(rest.len());
+	let tilt = rest[5..line_end].trim();
+	let mut words = rest[line_end..].split_whitespace();
+	if tilt == "INCLUDE" {
+	    // lamp to luminaire geometry, then tilt angles and multipliers we don't use
+	    IesProfile::numbers(&mut words, 1)?;
+	    let pairs = count(IesProfile::numbers(&mut words, 1)?[0])?;
+	    IesProfile::numbers(&mut words, pairs.checked_mul(2).ok_or_else(|| invalid("bad count"))?)?;
+	}
+	let header = IesProfile::numbers(&mut words, 13)?;
+	let (n_vertical, n_horizontal) = (count(header[3])?, count(header[4])?);
+	if header[5] as i32 != 1 {
+	    return Err(invalid("only type C photometry is supported"));
+	}
+	if n_vertical == 0 || n_horizontal == 0 {
+	    return Err(invalid("no angles"));
+	}
+	let vertical = IesProfile::numbers(&mut words, n_vertical)?;
+	let horizontal = IesProfile::numbers(&mut words, n_horizontal)?;
+	let (first, last) = (horizontal[0], horizontal[n_horizontal-1]);
+	if first != 0.0 && !(first == 90.0 && last == 270.0) {
+	    return Err(invalid("horizontal angles must start at 0, or run from 90 to 270"));
+	}
+	let mut candela = Vec::new();
+	for _ in 0..n_horizontal {
+	    candela.push(IesProfile::numbers(&mut words, n_vertical)?);
+	}
+	let peak = candela.iter().flat_map(|row| row.iter()).cloned().fold(0.0, f64::max);
+	if peak <= 0.0 {
+	    return Err(invalid("all candela values are zero"));
+	}
+	for row in candela.iter_mut() {
+	    for value in row.iter_mut() {
+		*value /= peak;
+	    }
+	}
+	Ok(IesProfile{vertical: vertical, horizontal: horizontal, candela: candela})
+    }
+    pub fn read_file(uri: &str) -> Result<IesProfile> {
+	IesProfile::read(&String::from_utf8_lossy(&std::fs::read(uri)?))
+    }
+    fn bracket(angles: &[f64], angle: f64) -> Option<(usize, usize, f64)> {
+	// neighbouring samples around angle and how far between them it lies
+	if angle < angles[0] || angle > angles[angles.len()-1] {
+	    return None;
+	}
+	let high = angles.iter().position(|&a| a >= angle).unwrap_or(angles.len()-1);
+	if high == 0 {
+	    return Some((0, 0, 0.0));
+	}
+	let low = high-1;
+	let span = angles[high]-angles[low];
+	Some((low, high, if span > 0.0 {(angle-angles[low])/span} else {0.0}))
+    }
+    fn fold_horizontal(&self, angle: f64) -> f64 {
+	// the range of horizontal angles says which symmetry the file relies on
+	let (first, last) = (self.horizontal[0], self.horizontal[self.horizontal.len()-1]);
+	if first == 90.0 {
+	    // symmetric about the 90-270 plane
+	    if angle < 90.0 {180.0-angle} else if angle > 270.0 {540.0-angle} else {angle}
+	} else if self.horizontal.len() == 1 || last <= 0.0 {
+	    0.0 // the same all the way round
+	} else if last <= 90.0 {
+	    let a = angle%180.0;
+	    if a > 90.0 {180.0-a} else {a}
+	} else if last <= 180.0 {
+	    if angle > 180.0 {360.0-angle} else {angle}
+	} else {
+	    angle
+	}
+    }
+    pub fn get_factor(&self, direction: Vector3<f64>, nadir: Vector3<f64>, c0: Vector3<f64>) -> f64 {
+	// relative intensity leaving in direction, for a profile whose 0 degree axis is nadir,
+	// with horizontal angle 0 towards c0 and 90 counter-clockwise from it, seen from above
+	let along = c0 - nadir*c0.dot(nadir);
+	let tangent = if along.magnitude2() > 1e-12 {along.normalize()} else {sampling::orthonormal_basis(nadir).0};
+	let bitangent = (-nadir).cross(tangent);
+	let direction = direction.normalize();
+	let vertical = direction.dot(nadir).max(-1.0).min(1.0).acos().to_degrees();
+	let horizontal = direction.dot(bitangent).atan2(direction.dot(tangent)).to_degrees();
+	let horizontal = self.fold_horizontal(if horizontal < 0.0 {horizontal+360.0} else {horizontal});
+	let (v0, v1, tv) = match IesProfile::bracket(&self.vertical, vertical) {
+	    Some(b) => b,
+	    None => return 0.0 // outside the measured range
+	};
+	let last = self.horizontal.len()-1;
+	let (h0, h1, th) = IesProfile::bracket(&self.horizontal, horizontal).unwrap_or((last, last, 0.0));
+	let row = |h: usize| self.candela[h][v0]*(1.0-tv) + self.candela[h][v1]*tv;
+	row(h0)*(1.0-th) + row(h1)*th
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(tilt: &str, n_vertical: &str, n_horizontal: &str) -> String {
+	// a downlight, brightest straight down
+	format!("IESNA:LM-63-2002\nTILT={}\n1 1000 1 {} {} 1 1 0 0 0 1 1 100\n0 90\n0\n100 50\n", tilt, n_vertical, n_horizontal)
+    }
+
+    #[test]
+    fn reads_a_profile() {
+	let profile = IesProfile::read(&file("NONE", "2", "1")).unwrap();
+	let down = Vector3{x: 0.0, y: 0.0, z: -1.0};
+	let c0 = Vector3{x: 1.0, y: 0.0, z: 0.0};
+	assert_eq!(profile.get_factor(down, down, c0), 1.0);
+	assert_eq!(profile.get_factor(c0, down, c0), 0.5);
+    }
+
+    #[test]
+    fn rejects_bad_counts() {
+	for &(n_vertical, n_horizontal) in &[("1e30", "1"), ("2", "-1"), ("inf", "1"), ("2.5", "1"), ("0", "1")] {
+	    let error = IesProfile::read(&file("NONE", n_vertical, n_horizontal)).err().unwrap();
+	    assert_eq!(error.kind(), ErrorKind::InvalidData);
+	}
+	let error = IesProfile::read(&file("INCLUDE\n1 1e30", "2", "1")).err().unwrap();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
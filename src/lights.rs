@@ -0,0 +1,465 @@
+use cgmath::Point3;
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::cgmath::MetricSpace;
+use crate::color::*;
+use crate::sampling;
+use crate::texture::ImageMap;
+use crate::ies::IesProfile;
+
+pub mod units { // conversions into the watts and W/m^2 lights are given in
+    use crate::color::Color;
+
+    pub const LUMENS_PER_WATT: f64 = 683.0; // peak luminous efficacy, exact for 555nm light
+
+    pub fn lumens(lumens: f64) -> f64 { // luminous flux to a light's power
+	lumens/LUMENS_PER_WATT
+    }
+    pub fn lux(lux: f64) -> f64 { // illuminance to a sun's irradiance
+	lux/LUMENS_PER_WATT
+    }
+    pub fn kelvin(kelvin: f64) -> Color { // black body colour at unit luminance, so it keeps the light's brightness
+	let color = Color::new_from_temperature(kelvin);
+	color/color.luminance()
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff { // how a local light dims with distance
+    InverseSquare, // physically correct
+    Linear,
+    None
+}
+
+impl Falloff {
+    pub fn attenuate(&self, power: f64, distance: f64, range: f64) -> f64 {
+	// power in watts spread over a sphere, faded smoothly to nothing at range
+	if distance >= range {
+	    return 0.0;
+	}
+	let spread = match *self {
+	    Falloff::InverseSquare => distance.powi(2),
+	    Falloff::Linear => distance,
+	    Falloff::None => 1.0
+	};
+	let window = if range.is_finite() {(1.0-(distance/range).powi(4)).powi(2)} else {1.0};
+	power*window/(4.0*std::f64::consts::PI*spread.max(1e-12))
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LightLink { // which objects a light shines on, by index in Scene::objects
+    All,
+    Include(Vec<usize>),
+    Exclude(Vec<usize>)
+}
+
+impl LightLink {
+    pub fn illuminates(&self, index: usize) -> bool {
+	match *self {
+	    LightLink::All => true,
+	    LightLink::Include(ref objects) => objects.contains(&index),
+	    LightLink::Exclude(ref objects) => !objects.contains(&index)
+	}
+    }
+}
+
+
+pub struct Sun {
+    pub direction: Vector3<f64>, // the way the light travels
+    pub color: Color,
+    pub irradiance: f64,       // W/m^2 on a surface facing the sun, see units::lux
+    pub angular_diameter: f64, // degrees, 0.53 for the real sun, 0 for hard shadows
+    pub samples: u32,
+    pub link: LightLink
+}
+
+
+impl Sun {
+    pub fn new(direction: Vector3<f64>,
+	       color: Color,
+	       irradiance: f64) -> Self {
+	Sun::new_soft(direction, color, irradiance, 0.0, 1)
+    }
+    pub fn new_soft(direction: Vector3<f64>,
+		    color: Color,
+		    irradiance: f64,
+		    angular_diameter: f64,
+		    samples: u32) -> Self {
+	Sun{direction: direction.normalize(), color: color, irradiance: irradiance,
+	    angular_diameter: angular_diameter.max(0.0), samples: samples.max(1), link: LightLink::All}
+    }
+    pub fn from_elevation(azimuth: f64, elevation: f64, // degrees, azimuth from +x towards +y, z up
+			  color: Color,
+			  irradiance: f64) -> Self {
+	let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+	let towards_sun = Vector3{x: elevation.cos()*azimuth.cos(), y: elevation.cos()*azimuth.sin(), z: elevation.sin()};
+	Sun::new(-towards_sun, color, irradiance)
+    }
+    pub fn from_lux(direction: Vector3<f64>, // about 100000 lux for a clear midday sun
+		    kelvin: f64,
+		    lux: f64) -> Self {
+	Sun::new(direction, units::kelvin(kelvin), units::lux(lux))
+    }
+}
+
+
+pub struct PointLight {
+    pub origin: Point3<f64>,
+    pub color: Color,
+    pub intensity: f64, // watts, see units::lumens
+    pub falloff: Falloff,
+    pub range: f64,     // no light beyond this distance
+    pub profile: Option<IesProfile>, // shaped by a photometric file, its nadir pointing down -z
+    pub profile_c0: Vector3<f64>,    // where the profile's 0 degree horizontal angle points
+    pub link: LightLink
+}
+
+
+impl PointLight {
+    pub fn new(position: Point3<f64>,
+	       color: Color,
+	       intensity: f64) -> Self {
+	PointLight{origin: position, color: color, intensity: intensity,
+		   falloff: Falloff::InverseSquare, range: std::f64::INFINITY,
+		   profile: None, profile_c0: Vector3{x: 1.0, y: 0.0, z: 0.0}, link: LightLink::All}
+    }
+    pub fn from_lumens(position: Point3<f64>, // a 60W incandescent bulb gives about 800 lumens at 2700K
+		       kelvin: f64,
+		       lumens: f64) -> Self {
+	PointLight::new(position, units::kelvin(kelvin), units::lumens(lumens))
+    }
+}
+
+
+pub struct Spot {
+    pub origin: Point3<f64>,
+    pub direction: Vector3<f64>,
+    pub color: Color,
+    pub intensity: f64, // watts of a point light it's cut out of
+    pub inner_cos: f64, // full intensity inside the inner cone
+    pub outer_cos: f64, // dark outside the outer cone
+    pub falloff: Falloff,
+    pub range: f64,
+    pub profile: Option<IesProfile>, // nadir along the spot's direction, applied on top of the cone
+    pub profile_c0: Vector3<f64>,    // where the profile's 0 degree horizontal angle points
+    pub link: LightLink
+}
+
+
+impl Spot {
+    pub fn new(position: Point3<f64>,
+	       direction: Vector3<f64>,
+	       color: Color,
+	       intensity: f64,
+	       inner_angle: f64, // half angles, in degrees
+	       outer_angle: f64) -> Self {
+	let outer = outer_angle.max(inner_angle);
+	Spot{origin: position, direction: direction.normalize(), color: color, intensity: intensity,
+	     inner_cos: inner_angle.to_radians().cos(), outer_cos: outer.to_radians().cos(),
+	     falloff: Falloff::InverseSquare, range: std::f64::INFINITY,
+	     profile: None, profile_c0: Vector3{x: 1.0, y: 0.0, z: 0.0}, link: LightLink::All}
+    }
+    pub fn get_cone_factor(&self, dir_to_light: Vector3<f64>) -> f64 {
+	let cos = self.direction.dot(-dir_to_light);
+	if cos <= self.outer_cos {0.0}
+	else if cos >= self.inner_cos {1.0}
+	else {
+	    let t = (cos-self.outer_cos)/(self.inner_cos-self.outer_cos);
+	    t*t*(3.0-2.0*t) // smoothstep
+	}
+    }
+}
+
+
+pub enum AreaShape {
+    Rectangle{center: Point3<f64>, u: Vector3<f64>, v: Vector3<f64>}, // edges, emits towards u x v
+    Disc{center: Point3<f64>, normal: Vector3<f64>, radius: f64},     // emits towards normal
+    Sphere{center: Point3<f64>, radius: f64}                          // emits everywhere
+}
+
+pub struct AreaLight {
+    pub shape: AreaShape,
+    pub color: Color,
+    pub intensity: f64, // watts, as for a PointLight of equal power
+    pub samples: u32,   // shadow rays per shading point, more gives smoother penumbrae
+    pub falloff: Falloff,
+    pub range: f64,
+    pub link: LightLink
+}
+
+
+impl AreaLight {
+    pub fn new(shape: AreaShape,
+	       color: Color,
+	       intensity: f64,
+	       samples: u32) -> Self {
+	let shape = match shape {
+	    AreaShape::Disc{center, normal, radius} => AreaShape::Disc{center: center, normal: normal.normalize(), radius: radius},
+	    _ => shape
+	};
+	AreaLight{shape: shape, color: color, intensity: intensity, samples: samples.max(1),
+		  falloff: Falloff::InverseSquare, range: std::f64::INFINITY, link: LightLink::All}
+    }
+    pub fn get_center(&self) -> Point3<f64> {
+	match self.shape {
+	    AreaShape::Rectangle{center, ..} => center,
+	    AreaShape::Disc{center, ..} => center,
+	    AreaShape::Sphere{center, ..} => center
+	}
+    }
+    fn sample_point(&self, point: Point3<f64>, s: f64, t: f64) -> (Point3<f64>, f64) {
+	// point on the light for (s, t) in [0, 1), with how much it emits towards `point`;
+	// flat shapes are lambertian and emit into one hemisphere, 4 cos against a point light's isotropic 1
+	match self.shape {
+	    AreaShape::Rectangle{center, u, v} => {
+		let location = center + u*(s-0.5) + v*(t-0.5);
+		let emit = 4.0*u.cross(v).normalize().dot((point-location).normalize()).max(0.0);
+		(location, emit)
+	    },
+	    AreaShape::Disc{center, normal, radius} => {
+		let (tangent, bitangent) = sampling::orthonormal_basis(normal);
+		let r = radius*s.sqrt();
+		let phi = 2.0*std::f64::consts::PI*t;
+		let location = center + tangent*(r*phi.cos()) + bitangent*(r*phi.sin());
+		let emit = 4.0*normal.dot((point-location).normalize()).max(0.0);
+		(location, emit)
+	    },
+	    AreaShape::Sphere{center, radius} => {
+		// uniform over the hemisphere facing the shaded point
+		let facing = (point-center).normalize();
+		let (tangent, bitangent) = sampling::orthonormal_basis(facing);
+		let z = s;
+		let r = (1.0-z*z).max(0.0).sqrt();
+		let phi = 2.0*std::f64::consts::PI*t;
+		let location = center + (tangent*(r*phi.cos()) + bitangent*(r*phi.sin()) + facing*z)*radius;
+		(location, 1.0)
+	    }
+	}
+    }
+}
+
+
+pub struct EnvironmentLight { // an equirectangular map lighting the scene from all around
+    pub map: ImageMap,
+    pub intensity: f64,
+    pub samples: u32,
+    pub link: LightLink,
+    width: usize,           // resolution of the sampling grid over the map
+    height: usize,
+    rows: Vec<f64>,         // cumulative distribution of picking each row
+    columns: Vec<Vec<f64>>, // cumulative distribution of each column within its row
+    total: f64,             // sum of all cell weights
+    dominant: Vector3<f64>  // brightness weighted mean direction, for nodes wanting a single one
+}
+
+
+impl EnvironmentLight {
+    pub fn new(map: ImageMap,
+	       intensity: f64,
+	       samples: u32) -> Self {
+	// cells are weighted by the mean luminance of the texels they cover times their solid angle,
+	// at most 512x256 of them, so a sun smaller than a cell still gets found
+	let pixvec = &map.data.pixvec;
+	let width = pixvec.width.min(512).max(1);
+	let height = pixvec.height.min(256).max(1);
+	let mut sums = vec![vec![0.0; width]; height];
+	let mut counts = vec![vec![0u32; width]; height];
+	for (y, line) in pixvec.iter().enumerate() {
+	    let i = y*height/pixvec.height;
+	    for (x, texel) in line.iter().enumerate() {
+		let j = x*width/pixvec.width;
+		sums[i][j] += texel.luminance().max(0.0);
+		counts[i][j] += 1;
+	    }
+	}
+	let mut rows = Vec::with_capacity(height);
+	let mut columns = Vec::with_capacity(height);
+	let mut total = 0.0;
+	let mut dominant = Vector3{x: 0.0, y: 0.0, z: 0.0};
+	for i in 0..height {
+	    let v = (i as f64 + 0.5)/(height as f64);
+	    let sin_theta = (v*std::f64::consts::PI).sin();
+	    let mut row = Vec::with_capacity(width);
+	    let mut row_total = 0.0;
+	    for j in 0..width {
+		let u = (j as f64 + 0.5)/(width as f64);
+		let weight = sums[i][j]/(counts[i][j].max(1) as f64)*sin_theta;
+		dominant += EnvironmentLight::direction(u, v)*weight;
+		row_total += weight;
+		row.push(row_total);
+	    }
+	    total += row_total;
+	    rows.push(total);
+	    columns.push(row);
+	}
+	let dominant = if dominant.magnitude2() > 0.0 {dominant.normalize()} else {Vector3{x: 0.0, y: 0.0, z: 1.0}};
+	EnvironmentLight{map: map, intensity: intensity, samples: samples.max(1), link: LightLink::All,
+			 width: width, height: height, rows: rows, columns: columns, total: total, dominant: dominant}
+    }
+    fn direction(u: f64, v: f64) -> Vector3<f64> {
+	// inverse of environment::Background::equirectangular_uv
+	let phi = (u-0.5)*2.0*std::f64::consts::PI;
+	let theta = v*std::f64::consts::PI;
+	Vector3{x: theta.sin()*phi.cos(), y: theta.sin()*phi.sin(), z: theta.cos()}
+    }
+    fn invert(cdf: &[f64], x: f64) -> (usize, f64) {
+	// bin holding x, and how far into it x lies
+	let target = x*cdf[cdf.len()-1];
+	let i = match cdf.binary_search_by(|c| c.partial_cmp(&target).unwrap()) {
+	    Ok(i) => i,
+	    Err(i) => i
+	}.min(cdf.len()-1);
+	let low = if i == 0 {0.0} else {cdf[i-1]};
+	let fraction = if cdf[i] > low {(target-low)/(cdf[i]-low)} else {0.5};
+	(i, fraction.max(0.0).min(1.0))
+    }
+    fn sample(&self, s: f64, t: f64) -> Option<LightSample> {
+	// direction chosen in proportion to cell weight, weighted by 1/pdf over the sphere
+	let (i, fy) = EnvironmentLight::invert(&self.rows, s);
+	let (j, fx) = EnvironmentLight::invert(&self.columns[i], t);
+	let row = &self.columns[i];
+	let cell = row[j] - if j == 0 {0.0} else {row[j-1]};
+	let (u, v) = ((j as f64 + fx)/(self.width as f64), (i as f64 + fy)/(self.height as f64));
+	let sin_theta = (v*std::f64::consts::PI).sin();
+	if cell <= 0.0 || sin_theta <= 0.0 {
+	    return None;
+	}
+	let pdf = cell/self.total*((self.width*self.height) as f64)
+	    /(2.0*std::f64::consts::PI*std::f64::consts::PI*sin_theta);
+	Some(LightSample{direction: EnvironmentLight::direction(u, v),
+			 distance: std::f64::MAX,
+			 weight: 1.0/(pdf*(self.samples as f64)),
+			 tint: self.map.get_color_uv(u, v)})
+    }
+}
+
+
+pub struct LightSample { // one shadow ray's worth of a light
+    pub direction: Vector3<f64>,
+    pub distance: f64,
+    pub weight: f64, // share of the light's intensity carried by this sample
+    pub tint: Color  // colour of this sample on top of the light's own
+}
+
+
+pub enum SceneLight {
+    Sun(Sun),
+    PointLight(PointLight),
+    Spot(Spot),
+    AreaLight(AreaLight),
+    Environment(EnvironmentLight)
+}
+
+impl SceneLight {
+    pub fn get_samples(&self, point: Point3<f64>) -> Vec<LightSample> {
+	match *self {
+	    SceneLight::Sun(ref s) if s.angular_diameter > 0.0 && s.samples > 1 => {
+		let cos_max = (s.angular_diameter.to_radians()/2.0).cos();
+		let weight = 1.0/(s.samples as f64);
+		sampling::stratified(s.samples).into_iter()
+		    .map(|(u, v)| LightSample{direction: sampling::cone(-s.direction, cos_max, u, v),
+					       distance: std::f64::MAX,
+					       weight: weight,
+					       tint: consts::WHITE})
+		    .collect()
+	    },
+	    SceneLight::Environment(ref e) => {
+		if e.total <= 0.0 {
+		    return Vec::new(); // black map
+		}
+		sampling::stratified(e.samples).into_iter()
+		    .filter_map(|(s, t)| e.sample(s, t))
+		    .collect()
+	    },
+	    SceneLight::AreaLight(ref a) => {
+		let mut samples = Vec::with_capacity(a.samples as usize);
+		let weight = 1.0/(a.samples as f64);
+		for (s, t) in sampling::stratified(a.samples) {
+		    let (location, emit) = a.sample_point(point, s, t);
+		    if emit > 0.0 {
+			samples.push(LightSample{direction: (location-point).normalize(),
+						 distance: location.distance(point),
+						 weight: weight*emit,
+						 tint: consts::WHITE});
+		    }
+		}
+		samples
+	    },
+	    _ => vec![LightSample{direction: self.get_direction(point), distance: self.dist_to(point), weight: 1.0, tint: consts::WHITE}]
+	}
+    }
+    pub fn dist_to(&self, point: Point3<f64>) -> f64 {
+	match *self {
+            SceneLight::Sun(ref _s) => std::f64::MAX,
+            SceneLight::Environment(ref _e) => std::f64::MAX,
+            SceneLight::PointLight(ref p) => {
+		p.origin.distance(point)
+	    },
+            SceneLight::Spot(ref s) => s.origin.distance(point),
+            SceneLight::AreaLight(ref a) => a.get_center().distance(point)
+        }
+    }
+    pub fn get_apparent_intensity(&self, dp: f64, dir_to_light: Vector3<f64>, distance: f64) -> f64 {
+	match *self {
+            SceneLight::Sun(ref s) => (
+		if dp < 0.0 {0.0}
+		else {dp*s.irradiance}
+	    ),
+            SceneLight::Environment(ref e) => {
+		if dp < 0.0 {0.0}
+		else {dp*e.intensity}
+	    },
+            SceneLight::PointLight(ref p) => {
+		if dp < 0.0 {0.0}
+		else {
+		    let shape = p.profile.as_ref().map_or(1.0, |ies| ies.get_factor(-dir_to_light, Vector3{x: 0.0, y: 0.0, z: -1.0}, p.profile_c0));
+		    dp*shape*p.falloff.attenuate(p.intensity, distance, p.range)
+		}
+	    },
+            SceneLight::Spot(ref s) => {
+		if dp < 0.0 {0.0}
+		else {
+		    let shape = s.profile.as_ref().map_or(1.0, |ies| ies.get_factor(-dir_to_light, s.direction, s.profile_c0));
+		    dp*s.get_cone_factor(dir_to_light)*shape*s.falloff.attenuate(s.intensity, distance, s.range)
+		}
+	    },
+            SceneLight::AreaLight(ref a) => {
+		if dp < 0.0 {0.0}
+		else {dp*a.falloff.attenuate(a.intensity, distance, a.range)}
+	    },
+        }
+    }
+    pub fn get_direction(&self, point: Point3<f64>) -> Vector3<f64> {
+	match *self {
+            SceneLight::Sun(ref s) => -s.direction,
+            SceneLight::Environment(ref e) => e.dominant,
+            SceneLight::PointLight(ref p) => {
+		(p.origin-point).normalize()
+	    },
+            SceneLight::Spot(ref s) => (s.origin-point).normalize(),
+            SceneLight::AreaLight(ref a) => (a.get_center()-point).normalize()
+        }
+    }
+    pub fn illuminates(&self, object_index: usize) -> bool {
+	let link = match *self {
+            SceneLight::Sun(ref s) => &s.link,
+            SceneLight::PointLight(ref p) => &p.link,
+            SceneLight::Spot(ref s) => &s.link,
+            SceneLight::AreaLight(ref a) => &a.link,
+            SceneLight::Environment(ref e) => &e.link
+        };
+	link.illuminates(object_index)
+    }
+    pub fn get_color(&self) -> &Color {
+	match *self {
+            SceneLight::Sun(ref s) => &s.color,
+            SceneLight::PointLight(ref p) => &p.color,
+            SceneLight::Spot(ref s) => &s.color,
+            SceneLight::AreaLight(ref a) => &a.color,
+            SceneLight::Environment(ref _e) => &consts::WHITE // per sample tint carries the colour
+        }
+    }
+}
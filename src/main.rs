@@ -5,15 +5,33 @@ extern crate gio;
 extern crate glib;
 extern crate gtk;
 extern crate cgmath;
+extern crate miniz_oxide;
 
 mod camera_math;
 mod pixvec;
 mod shapes;
 mod color;
+mod sampling;
+mod outline;
+mod conductor;
+mod procedural;
+mod texture;
+mod environment;
+mod radiance;
+mod openexr;
+mod lights;
+mod photon;
+mod ies;
 use crate::pixvec::*;
 use crate::camera_math::*;
 use crate::shapes::*;
 use crate::color::*;
+use crate::outline::*;
+use crate::conductor::presets;
+use crate::texture::*;
+use crate::environment::*;
+use crate::lights::*;
+use crate::photon::PhotonMap;
 use crate::cgmath::InnerSpace;
 
 use gio::prelude::*;
@@ -30,6 +48,10 @@ static HEIGHT_VIEWPORT : i32   = 2160;
 
 fn render_scene(scene: &mut Scene, pvec: &mut Pixvec) {
     let focal_point = scene.camera.get_focal_point();
+    let mut surfaces : Vec<Vec<Option<SurfaceSample>>> = Vec::new();
+    if scene.outline.is_some() {
+	surfaces.resize_with(pvec.height, || vec![None; pvec.width]);
+    }
     for i in 0..pvec.height {
 	for j in 0..pvec.width {
 	    let p = scene.camera.pixel_to_world(j, i);
@@ -40,8 +62,14 @@ fn render_scene(scene: &mut Scene, pvec: &mut Pixvec) {
 		    scene.white_balance = white_balance;
 		}
 	    } // else - no collision
+	    if scene.outline.is_some() {
+		surfaces[i][j] = ray.probe(scene).map(|(depth, normal, object)| SurfaceSample{depth: depth, normal: normal, object: object});
+	    }
 	}
     }
+    if let Some(outline) = &scene.outline {
+	outline.apply(&surfaces, pvec);
+    }
 }
 
 fn build_ui(application: &gtk::Application) {
@@ -50,22 +78,26 @@ fn build_ui(application: &gtk::Application) {
     window.set_title("Raytracer");
     window.set_position(gtk::WindowPosition::Center);
 
-    let metal_texture = ImageMap::new_from_file("assets/metal.png".to_string(), 2.0);
-    let static_texture = ImageMap::new_from_file("assets/static.jpg".to_string(), 5.0);
+    let mut textures = TextureCache::new();
+    let metal_texture = textures.image_map("assets/metal.png", 2.0);
+    let static_texture = textures.image_map("assets/static.jpg", 5.0);
 
     let mut static_nodes = Vec::new();
-    static_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-    static_nodes.push(Node::Reflect(ShadeReflect::new(0.25)));
+    static_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.2),
+					      Node::Diffuse(ShadeDiffuse::new(1.0)),
+					      Node::Reflect(ShadeReflect::new(1.0)))));
     let static_material = Material::new(Some(Texture::ImageMap(static_texture)), 0.3, static_nodes);
     
     let mut untextured_nodes = Vec::new();
-    untextured_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-    untextured_nodes.push(Node::Reflect(ShadeReflect::new(0.1)));
+    untextured_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Fresnel(1.5),
+						  Node::Diffuse(ShadeDiffuse::new(1.0)),
+						  Node::Reflect(ShadeReflect::new(1.0)))));
     let untextured_material = Material::new(None, 0.9, untextured_nodes);
     
     let mut chrome_nodes = Vec::new();
-    chrome_nodes.push(Node::Diffuse(ShadeDiffuse::new(0.15)));
-    chrome_nodes.push(Node::Reflect(ShadeReflect::new(1.0)));
+    chrome_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.85),
+					      Node::Diffuse(ShadeDiffuse::new(1.0)),
+					      Node::Reflect(ShadeReflect::new_metal(1.0, presets::CHROMIUM)))));
     let chrome_material = Material::new(Some(Texture::Color(Color::new_from_linear(71, 221, 255))), 0.8, chrome_nodes);
     
     let mut blue_nodes = Vec::new();
@@ -77,9 +109,13 @@ fn build_ui(application: &gtk::Application) {
     let backdrop_material = Material::new(Some(Texture::Color(Color::new_from_linear(50, 50, 255))), 0.5, backdrop_nodes);
     
     let mut metal_nodes = Vec::new();
-    metal_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-    metal_nodes.push(Node::Reflect(ShadeReflect::new(1.0)));
-    let metal_material = Material::new(Some(Texture::ImageMap(metal_texture)), 1.0, metal_nodes);
+    metal_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.5),
+					     Node::Diffuse(ShadeDiffuse::new(1.0)),
+					     Node::Reflect(ShadeReflect::new(1.0)))));
+    metal_nodes.push(Node::AmbientOcclusion(ShadeAmbientOcclusion::new(0.1, color::consts::WHITE, 16, 1.0)));
+    let mut metal_material = Material::new(Some(Texture::ImageMap(metal_texture)), 1.0, metal_nodes);
+    metal_material.bump_map = Some(textures.image_map_linear("assets/metal.png", 2.0));
+    metal_material.bump_strength = 0.5;
     
     let mut objects : Vec<SceneObject> = Vec::new();
 
@@ -91,41 +127,48 @@ fn build_ui(application: &gtk::Application) {
     objects.push(SceneObject::Plane(Plane::new(Point3{x: 100.0, y: 0.0, z: 0.0}, Vector3{x: 1.0, y: 0.0, z: 0.0}, backdrop_material)));
     
     for i in (-20..100).step_by(2) {
-	let red_texture = ImageMap::new_from_file("assets/fire.jpg".to_string(), 5.0);
+	let red_texture = textures.image_map("assets/fire.jpg", 5.0);
 	let mut red_nodes = Vec::new();
-	red_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-	red_nodes.push(Node::Reflect(ShadeReflect::new(0.05)));
+	red_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.05),
+					       Node::Diffuse(ShadeDiffuse::new(1.0)),
+					       Node::Reflect(ShadeReflect::new(1.0)))));
 	let red_material = Material::new(Some(Texture::ImageMap(red_texture)), 0.9, red_nodes);
 	objects.push(SceneObject::Sphere(Sphere::new(Point3{x: 1.0+i as f64, y: 1.0, z: 0.5}, 0.3, red_material)));
     }
 
     for i in (-20..100).step_by(2) {
-	let red_texture = ImageMap::new_from_file("assets/fire.jpg".to_string(), 5.0);
+	let red_texture = textures.image_map("assets/fire.jpg", 5.0);
 	let mut red_nodes = Vec::new();
-	red_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-	red_nodes.push(Node::Reflect(ShadeReflect::new(0.05)));
+	red_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.05),
+					       Node::Diffuse(ShadeDiffuse::new(1.0)),
+					       Node::Reflect(ShadeReflect::new(1.0)))));
 	let red_material = Material::new(Some(Texture::ImageMap(red_texture)), 0.9, red_nodes);
 	objects.push(SceneObject::Sphere(Sphere::new(Point3{x: 1.0+i as f64, y: -1.0, z: 0.5}, 0.3, red_material)));
     }
     /*
-	let red_texture = ImageMap::new_from_file("assets/fire.jpg".to_string(), 5.0);
+	let red_texture = textures.image_map("assets/fire.jpg", 5.0);
 	let mut red_nodes = Vec::new();
-	red_nodes.push(Node::Diffuse(ShadeDiffuse::new(1.0)));
-	red_nodes.push(Node::Reflect(ShadeReflect::new(0.05)));
+	red_nodes.push(Node::Mix(ShadeMix::new(MixFactor::Constant(0.05),
+					       Node::Diffuse(ShadeDiffuse::new(1.0)),
+					       Node::Reflect(ShadeReflect::new(1.0)))));
 	let red_material = Material::new(Some(Texture::ImageMap(red_texture)), 0.9, red_nodes);
 	objects.push(SceneObject::Sphere(Sphere::new(Point3{x: 3.0 as f64, y: -0.5, z: 0.5}, 0.3, red_material)));
      */
     
+    println!("Loaded {} textures using {:.1} MiB", textures.len(), textures.memory_usage() as f64/(1024.0*1024.0));
+
     let mut lights : Vec<SceneLight>  = Vec::new();
-    lights.push(SceneLight::PointLight(PointLight::new(Point3{x: 60.0, y: 0.0, z: 150.0},
-						       color::consts::WHITE,
-						       1500000.0)));
+    lights.push(SceneLight::Sun(Sun::new_soft(Vector3{x: -60.0, y: 0.0, z: -150.0},
+					      color::consts::WHITE,
+					      1.5,
+					      0.53,
+					      8)));
     lights.push(SceneLight::PointLight(PointLight::new(Point3{x: -0.5, y: -3.0, z: 15.0},
 						       color::consts::WHITE,
-						       15000.0)));
+						       5000.0)));
     lights.push(SceneLight::PointLight(PointLight::new(Point3{x: 0.0, y: 3.0, z: 15.0},
 						       color::consts::WHITE,
-						       15000.0)));
+						       5000.0)));
     let mut scene = Scene{camera: Camera{location: Point3{x: 0.0, y: 0.0, z: -0.1},
 					 rotation: Vector3{x: 0.0, y: 0.2, z: 0.0},
 					 focal_length: 0.4,
@@ -134,7 +177,12 @@ fn build_ui(application: &gtk::Application) {
 					 hy: 0.375},
 			  objects: objects,
 			  lights: lights,
+			  outline: None,
+			  background: Background::Color(color::consts::BLACK),
+			  light_sampling: LightSampling::Sum,
+			  caustics: None,
 			  white_balance: 0.0};
+    scene.caustics = Some(PhotonMap::new(&scene, 100000, 64, 0.2));
     
     let mut pvec = Pixvec::new(WIDTH_RENDER, HEIGHT_RENDER);
     render_scene(&mut scene, &mut pvec);
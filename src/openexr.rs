@@ -0,0 +1,334 @@
+use std::io::{Error, ErrorKind, Result};
+use crate::color::*;
+use crate::pixvec::*;
+
+// OpenEXR reader for single part scanline images with NONE, RLE, ZIPS or ZIP compression
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("OpenEXR: {}", message))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+	let end = match self.pos.checked_add(n) {
+	    Some(end) if end <= self.bytes.len() => end,
+	    _ => return Err(invalid("unexpected end of file"))
+	};
+	let slice = &self.bytes[self.pos..end];
+	self.pos = end;
+	Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8> {
+	Ok(self.take(1)?[0])
+    }
+    fn i32(&mut self) -> Result<i32> {
+	let b = self.take(4)?;
+	Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn size(&mut self) -> Result<usize> { // i32 byte count, which mustn't be negative
+	let size = self.i32()?;
+	if size < 0 {
+	    return Err(invalid("negative size"));
+	}
+	Ok(size as usize)
+    }
+    fn u64(&mut self) -> Result<u64> {
+	let b = self.take(8)?;
+	Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+    fn string(&mut self) -> Result<String> { // null terminated
+	let start = self.pos;
+	while self.u8()? != 0 {}
+	Ok(String::from_utf8_lossy(&self.bytes[start..self.pos-1]).to_string())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PixelType {
+    Uint,
+    Half,
+    Float
+}
+
+impl PixelType {
+    fn size(&self) -> usize {
+	if *self == PixelType::Half {2} else {4}
+    }
+}
+
+struct Channel {
+    name: String,
+    pixel_type: PixelType
+}
+
+fn half_to_f64(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 {-1.0} else {1.0};
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f64;
+    match exponent {
+	0 => sign*mantissa*2f64.powi(-24),                 // subnormal
+	31 => if mantissa == 0.0 {sign*std::f64::INFINITY} else {std::f64::NAN},
+	_ => sign*(1.0+mantissa/1024.0)*2f64.powi(exponent-15)
+    }
+}
+
+fn read_value(data: &[u8], pixel_type: PixelType) -> f64 {
+    match pixel_type {
+	PixelType::Half => half_to_f64(u16::from_le_bytes([data[0], data[1]])),
+	PixelType::Float => f32::from_le_bytes([data[0], data[1], data[2], data[3]]) as f64,
+	PixelType::Uint => u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as f64
+    }
+}
+
+fn undo_predictor(data: &mut Vec<u8>) -> Vec<u8> {
+    // RLE and ZIP store byte deltas, split into two interleaved halves
+    for i in 1..data.len() {
+	data[i] = data[i-1].wrapping_add(data[i]).wrapping_sub(128);
+    }
+    let half = (data.len()+1)/2;
+    let mut out = Vec::with_capacity(data.len());
+    for i in 0..half {
+	out.push(data[i]);
+	if half+i < data.len() {
+	    out.push(data[half+i]);
+	}
+    }
+    out
+}
+
+fn decompress_rle(data: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected);
+    let mut i = 0;
+    while i < data.len() {
+	let count = data[i] as i8;
+	i += 1;
+	if count < 0 { // literal run
+	    let n = (-(count as i32)) as usize;
+	    if i+n > data.len() {
+		return Err(invalid("bad RLE literal"));
+	    }
+	    out.extend_from_slice(&data[i..i+n]);
+	    i += n;
+	} else { // repeated byte
+	    let value = *data.get(i).ok_or_else(|| invalid("bad RLE run"))?;
+	    i += 1;
+	    for _ in 0..(count as usize+1) {
+		out.push(value);
+	    }
+	}
+    }
+    Ok(out)
+}
+
+pub fn read(bytes: &[u8]) -> Result<Pixvec> {
+    let mut reader = Reader{bytes: bytes, pos: 0};
+    if reader.take(4)? != [0x76, 0x2f, 0x31, 0x01] {
+	return Err(invalid("bad magic number"));
+    }
+    let version = reader.i32()?;
+    if version & 0xff != 2 || version & 0x1200 != 0 { // tiled or multi-part
+	return Err(invalid("only single part scanline files are supported"));
+    }
+
+    let mut channels: Vec<Channel> = Vec::new();
+    let mut compression = None;
+    let mut data_window = None;
+    loop { // attributes until an empty name
+	let name = reader.string()?;
+	if name.is_empty() {
+	    break;
+	}
+	let _kind = reader.string()?;
+	let size = reader.size()?;
+	let value = reader.take(size)?;
+	let mut attribute = Reader{bytes: value, pos: 0};
+	match name.as_str() {
+	    "channels" => loop {
+		let channel_name = attribute.string()?;
+		if channel_name.is_empty() {
+		    break;
+		}
+		let pixel_type = match attribute.i32()? {
+		    0 => PixelType::Uint,
+		    1 => PixelType::Half,
+		    2 => PixelType::Float,
+		    _ => return Err(invalid("unknown pixel type"))
+		};
+		attribute.take(4)?; // pLinear and reserved
+		if attribute.i32()? != 1 || attribute.i32()? != 1 {
+		    return Err(invalid("subsampled channels are not supported"));
+		}
+		channels.push(Channel{name: channel_name, pixel_type: pixel_type});
+	    },
+	    "compression" => compression = Some(attribute.u8()?),
+	    "dataWindow" => data_window = Some((attribute.i32()?, attribute.i32()?, attribute.i32()?, attribute.i32()?)),
+	    _ => {}
+	}
+    }
+    let compression = compression.ok_or_else(|| invalid("missing compression"))?;
+    let (xmin, ymin, xmax, ymax) = data_window.ok_or_else(|| invalid("missing dataWindow"))?;
+    let (width, height) = ((xmax as i64)-(xmin as i64)+1, (ymax as i64)-(ymin as i64)+1);
+    if width <= 0 || height <= 0 || width*height > 1 << 28 { // a quarter billion pixels is plenty
+	return Err(invalid("bad dataWindow"));
+    }
+    let (width, height) = (width as usize, height as usize);
+    let lines_per_chunk = match compression {
+	0 | 1 | 2 => 1, // NONE, RLE, ZIPS
+	3 => 16,        // ZIP
+	_ => return Err(invalid("unsupported compression, use NONE, RLE, ZIPS or ZIP"))
+    };
+    let find = |name: &str| channels.iter().position(|c| c.name == name || c.name.ends_with(&format!(".{}", name)));
+    let (r, g, b, a) = (find("R"), find("G"), find("B"), find("A"));
+    let y_channel = find("Y"); // luminance only images
+    if (r.is_none() || g.is_none() || b.is_none()) && y_channel.is_none() {
+	return Err(invalid("needs R, G and B or Y channels"));
+    }
+
+    let chunks = (height+lines_per_chunk-1)/lines_per_chunk;
+    let mut offsets = Vec::with_capacity(chunks);
+    for _ in 0..chunks {
+	offsets.push(reader.u64()? as usize);
+    }
+    let line_size: usize = channels.iter().map(|c| c.pixel_type.size()*width).sum();
+
+    let mut pvec = Pixvec::new(width, height);
+    for offset in offsets {
+	let mut chunk = Reader{bytes: bytes, pos: offset};
+	let y = (chunk.i32()? as i64)-(ymin as i64);
+	if y < 0 || y >= height as i64 {
+	    return Err(invalid("chunk outside the dataWindow"));
+	}
+	let y = y as usize;
+	let size = chunk.size()?;
+	let packed = chunk.take(size)?;
+	let lines = lines_per_chunk.min(height.saturating_sub(y));
+	let expected = line_size*lines;
+	let data = if size == expected {
+	    packed.to_vec() // stored raw when compression wouldn't help
+	} else {
+	    match compression {
+		0 => packed.to_vec(),
+		1 => undo_predictor(&mut decompress_rle(packed, expected)?),
+		_ => undo_predictor(&mut miniz_oxide::inflate::decompress_to_vec_zlib(packed).map_err(|_| invalid("bad zip data"))?)
+	    }
+	};
+	if data.len() < expected {
+	    return Err(invalid("chunk is too short"));
+	}
+	for line in 0..lines {
+	    let row = y+line;
+	    if row >= height {
+		break;
+	    }
+	    // channels are stored one after another for each line, in chlist order
+	    let mut starts = Vec::with_capacity(channels.len());
+	    let mut start = line*line_size;
+	    for channel in channels.iter() {
+		starts.push(start);
+		start += channel.pixel_type.size()*width;
+	    }
+	    let value = |index: usize, x: usize| {
+		let channel = &channels[index];
+		let at = starts[index]+x*channel.pixel_type.size();
+		read_value(&data[at..], channel.pixel_type)
+	    };
+	    for x in 0..width {
+		pvec[row][x] = match (r, g, b) {
+		    (Some(r), Some(g), Some(b)) => Color::new(value(r, x), value(g, x), value(b, x)),
+		    _ => {
+			let l = value(y_channel.unwrap(), x);
+			Color::new(l, l, l)
+		    }
+		};
+		if let Some(a) = a {
+		    pvec.set_alpha(row, x, value(a, x));
+		}
+	    }
+	}
+    }
+    Ok(pvec)
+}
+
+pub fn read_file(uri: &str) -> Result<Pixvec> {
+    read(&std::fs::read(uri)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(bytes: &mut Vec<u8>, name: &str, kind: &str, size: i32, value: &[u8]) {
+	for text in &[name, kind] {
+	    bytes.extend_from_slice(text.as_bytes());
+	    bytes.push(0);
+	}
+	bytes.extend_from_slice(&size.to_le_bytes());
+	bytes.extend_from_slice(value);
+    }
+
+    fn header(data_window: [i32; 4]) -> Vec<u8> {
+	// uncompressed half float Y channel
+	let mut bytes = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+	let mut channels = b"Y\0".to_vec();
+	for value in &[1i32, 0, 1, 1] {
+	    channels.extend_from_slice(&value.to_le_bytes());
+	}
+	channels.push(0);
+	attribute(&mut bytes, "channels", "chlist", channels.len() as i32, &channels);
+	attribute(&mut bytes, "compression", "compression", 1, &[0]);
+	let window: Vec<u8> = data_window.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+	attribute(&mut bytes, "dataWindow", "box2i", 16, &window);
+	bytes.push(0);
+	bytes
+    }
+
+    fn image(offset: u64, y: i32, size: i32) -> Vec<u8> {
+	// one pixel of 1.0, given where its chunk claims to be
+	let mut bytes = header([0, 0, 0, 0]);
+	bytes.extend_from_slice(&offset.to_le_bytes());
+	bytes.extend_from_slice(&y.to_le_bytes());
+	bytes.extend_from_slice(&size.to_le_bytes());
+	bytes.extend_from_slice(&[0x00, 0x3c]);
+	bytes
+    }
+
+    fn is_invalid(result: Result<Pixvec>) -> bool {
+	matches!(result, Err(ref error) if error.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn reads_one_pixel() {
+	let offset = header([0, 0, 0, 0]).len() as u64+8;
+	let pvec = read(&image(offset, 0, 2)).unwrap();
+	assert_eq!((pvec.width, pvec.height), (1, 1));
+	assert_eq!(pvec[0][0].luminance(), Color::new(1.0, 1.0, 1.0).luminance());
+    }
+
+    #[test]
+    fn rejects_negative_attribute_size() {
+	let mut bytes = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+	attribute(&mut bytes, "channels", "chlist", -1, &[0; 8]);
+	assert!(is_invalid(read(&bytes)));
+    }
+
+    #[test]
+    fn rejects_bad_data_windows() {
+	for window in &[[0, 0, -1, 0], [0, 0, std::i32::MAX, std::i32::MAX], [std::i32::MAX, 0, std::i32::MIN, 0]] {
+	    assert!(is_invalid(read(&header(*window))));
+	}
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+	let offset = header([0, 0, 0, 0]).len() as u64+8;
+	assert!(is_invalid(read(&image(std::u64::MAX, 0, 2))));
+	assert!(is_invalid(read(&image(offset, 0, -1))));
+	assert!(is_invalid(read(&image(offset, std::i32::MIN, 2))));
+	assert!(is_invalid(read(&image(offset, 0, std::i32::MAX))));
+    }
+}
@@ -0,0 +1,54 @@
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::color::*;
+use crate::pixvec::*;
+
+#[derive(Clone, Copy)]
+pub struct SurfaceSample { // what a primary ray hit, used by image-space passes
+    pub depth: f64,
+    pub normal: Vector3<f64>,
+    pub object: usize
+}
+
+pub struct Outline {
+    pub color: Color,
+    pub thickness: usize,       // search radius in pixels
+    pub depth_threshold: f64,   // relative depth jump that counts as an edge
+    pub normal_threshold: f64   // angle between normals in radians that counts as a crease
+}
+
+impl Outline {
+    pub fn new(color: Color, thickness: usize, depth_threshold: f64, normal_threshold: f64) -> Self {
+	Outline{color: color, thickness: thickness, depth_threshold: depth_threshold, normal_threshold: normal_threshold}
+    }
+    fn is_edge(&self, a: &Option<SurfaceSample>, b: &Option<SurfaceSample>) -> bool {
+	match (a, b) {
+	    (None, None) => false,
+	    (Some(a), Some(b)) => {
+		a.object != b.object
+		    || (a.depth-b.depth).abs() > self.depth_threshold*a.depth.min(b.depth)
+		    || a.normal.dot(b.normal) < self.normal_threshold.cos()
+	    },
+	    _ => true // silhouette against the background
+	}
+    }
+    pub fn apply(&self, samples: &Vec<Vec<Option<SurfaceSample>>>, pvec: &mut Pixvec) {
+	let radius = self.thickness as isize;
+	for i in 0..pvec.height {
+	    for j in 0..pvec.width {
+		'search: for di in -radius..=radius {
+		    for dj in -radius..=radius {
+			let (ni, nj) = (i as isize+di, j as isize+dj);
+			if ni < 0 || nj < 0 || ni >= pvec.height as isize || nj >= pvec.width as isize {
+			    continue;
+			}
+			if self.is_edge(&samples[i][j], &samples[ni as usize][nj as usize]) {
+			    pvec[i][j] = self.color;
+			    break 'search;
+			}
+		    }
+		}
+	    }
+	}
+    }
+}
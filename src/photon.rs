@@ -0,0 +1,219 @@
+use cgmath::Point3;
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::cgmath::MetricSpace;
+use crate::color::*;
+use crate::sampling;
+use crate::lights::*;
+use crate::shapes::*;
+
+static PHOTON_BOUNCES : u32 = 16;
+static PHOTON_BIAS    : f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub position: Point3<f64>,
+    pub direction: Vector3<f64>, // the way it was travelling when it landed
+    pub power: Color             // watts
+}
+
+
+pub struct PhotonMap { // caustic photons in a balanced kd-tree
+    photons: Vec<Photon>,
+    axes: Vec<usize>, // split axis of the node at the same index
+    gather: usize,    // photons used per estimate
+    radius: f64,      // furthest a photon is looked for
+    casters: Vec<usize> // indices in scene.objects of the objects photons were shot at
+}
+
+
+impl PhotonMap {
+    pub fn new(scene: &Scene, photons_per_light: usize, gather: usize, radius: f64) -> Self {
+	// shoots photons from every light at the reflective and refractive objects,
+	// keeping those landing on diffuse surfaces after at least one specular bounce
+	let casters: Vec<(&SceneObject, Point3<f64>, f64)> = scene.objects.iter()
+	    .filter(|o| o.get_nodes().iter().any(|node| node.is_specular()))
+	    .filter_map(|o| o.bounding_sphere().map(|(center, radius)| (o, center, radius)))
+	    .collect();
+	let mut photons = Vec::new();
+	if !casters.is_empty() {
+	    let per_caster = (photons_per_light/casters.len()).max(1) as u32;
+	    for light in scene.lights.iter() {
+		for &(caster, center, radius) in casters.iter() {
+		    PhotonMap::emit(scene, light, caster, center, radius, per_caster, &mut photons);
+		}
+	    }
+	}
+	let mut axes = vec![0; photons.len()];
+	PhotonMap::balance(&mut photons, &mut axes);
+	let casters = casters.iter().map(|&(caster, _, _)| scene.object_index(caster)).collect();
+	PhotonMap{photons: photons, axes: axes, gather: gather.max(1), radius: radius, casters: casters}
+    }
+    pub fn casters_for(&self, light: &SceneLight) -> &[usize] {
+	// objects whose light from `light` arrives as photons, so direct lighting mustn't pass through them too
+	match *light {
+	    SceneLight::Environment(_) => &[],
+	    _ => &self.casters
+	}
+    }
+    fn emit(scene: &Scene, light: &SceneLight, caster: &SceneObject, center: Point3<f64>, radius: f64, count: u32, photons: &mut Vec<Photon>) {
+	// aims photons at one caster's bounding sphere, each carrying its share of the power sent that way
+	match *light {
+	    SceneLight::Environment(_) => (), // no single origin to shoot from
+	    SceneLight::Sun(ref s) => {
+		// a disc across the caster, facing the sun
+		let (tangent, bitangent) = sampling::orthonormal_basis(s.direction);
+		let power = s.color*(s.irradiance*std::f64::consts::PI*radius*radius/(count as f64));
+		for (u, v) in sampling::stratified(count) {
+		    let r = radius*u.sqrt();
+		    let phi = 2.0*std::f64::consts::PI*v;
+		    let offset = tangent*(r*phi.cos()) + bitangent*(r*phi.sin());
+		    let origin = center + offset - s.direction*(radius*1e4);
+		    PhotonMap::trace(scene, Ray{origin: origin, direction: s.direction}, power, caster, photons);
+		}
+	    },
+	    _ => {
+		// point, spot and area lights all shoot from their centre
+		let direction = light.get_direction(center);
+		let distance = light.dist_to(center);
+		if distance <= radius {
+		    return; // light inside the caster
+		}
+		let origin = center + direction*distance;
+		let cos_max = (1.0-(radius/distance).powi(2)).sqrt();
+		let solid_angle = 2.0*std::f64::consts::PI*(1.0-cos_max);
+		for (u, v) in sampling::stratified(count) {
+		    let out = sampling::cone(-direction, cos_max, u, v);
+		    // irradiance at the caster as direct lighting sees it, falloff, range and all,
+		    // times the area the cone covers there; nothing when the caster is out of range
+		    let irradiance = light.get_apparent_intensity(1.0, -out, distance);
+		    if irradiance > 0.0 {
+			let power = (*light.get_color())*(irradiance*distance*distance*solid_angle/(count as f64));
+			PhotonMap::trace(scene, Ray{origin: origin, direction: out}, power, caster, photons);
+		    }
+		}
+	    }
+	}
+    }
+    fn trace(scene: &Scene, ray: Ray, power: Color, caster: &SceneObject, photons: &mut Vec<Photon>) {
+	let mut ray = ray;
+	let mut power = power;
+	for bounce in 0..PHOTON_BOUNCES {
+	    let (dist, location, normal, obj) = match ray.closest_intersect(scene, RayKind::Refraction) {
+		Some(hit) => hit,
+		None => return
+	    };
+	    if bounce == 0 && !std::ptr::eq(obj, caster) {
+		return; // blocked, or another caster's, which has photons aimed at it already
+	    }
+	    let dp = ray.direction.dot(normal);
+	    if dp > 0.0 { // came through the object's inside
+		power = power*obj.get_material().attenuation(dist);
+	    }
+	    if bounce > 0 && obj.get_nodes().iter().any(|node| node.is_diffuse()) {
+		photons.push(Photon{position: location, direction: ray.direction, power: power});
+	    }
+	    // russian roulette between mirroring, refracting and being absorbed
+	    let reflection = obj.get_reflection(&location, normal, ray.direction);
+	    let transmission = obj.get_transmission(&location, normal, ray.direction);
+	    let index = obj.get_refraction_index(&location);
+	    let kr = match index {
+		Some(index) => if dp < 0.0 {fresnel(dp, 1.0, index)} else {fresnel(dp, index, 1.0)},
+		None => 0.0
+	    };
+	    let mirrored = consts::WHITE*reflection + transmission*kr;
+	    let refracted = transmission*(1.0-kr);
+	    let (p_mirror, p_refract) = (mirrored.luminance().min(1.0), refracted.luminance().min(1.0));
+	    let facing = if dp < 0.0 {normal} else {-normal};
+	    let xi = sampling::random();
+	    if xi < p_mirror {
+		let direction = (ray.direction-2.0*dp*normal).normalize();
+		power = power*mirrored/p_mirror;
+		ray = Ray{origin: location+facing*PHOTON_BIAS, direction: direction};
+	    } else if xi < p_mirror+p_refract {
+		let eta = if dp < 0.0 {1.0/index.unwrap_or(1.0)} else {index.unwrap_or(1.0)};
+		match ShadeRefract::refract_direction(ray.direction, facing, eta) {
+		    Some(direction) => {
+			power = power*refracted/p_refract;
+			ray = Ray{origin: location-facing*PHOTON_BIAS, direction: direction.normalize()};
+		    },
+		    None => return
+		}
+	    } else {
+		return; // absorbed, or diffusely scattered which isn't a caustic
+	    }
+	}
+    }
+    fn coordinate(point: &Point3<f64>, axis: usize) -> f64 {
+	match axis {
+	    0 => point.x,
+	    1 => point.y,
+	    _ => point.z
+	}
+    }
+    fn balance(photons: &mut [Photon], axes: &mut [usize]) {
+	// median split along the widest axis, the median sits in the middle of its range
+	if photons.len() <= 1 {
+	    return;
+	}
+	let mut low = [std::f64::MAX; 3];
+	let mut high = [std::f64::MIN; 3];
+	for photon in photons.iter() {
+	    for axis in 0..3 {
+		let c = PhotonMap::coordinate(&photon.position, axis);
+		low[axis] = low[axis].min(c);
+		high[axis] = high[axis].max(c);
+	    }
+	}
+	let axis = (0..3).max_by(|&a, &b| (high[a]-low[a]).partial_cmp(&(high[b]-low[b])).unwrap()).unwrap();
+	photons.sort_by(|a, b| PhotonMap::coordinate(&a.position, axis)
+			.partial_cmp(&PhotonMap::coordinate(&b.position, axis)).unwrap());
+	let middle = photons.len()/2;
+	axes[middle] = axis;
+	let (left_photons, right_photons) = photons.split_at_mut(middle);
+	let (left_axes, right_axes) = axes.split_at_mut(middle);
+	PhotonMap::balance(left_photons, left_axes);
+	PhotonMap::balance(&mut right_photons[1..], &mut right_axes[1..]);
+    }
+    fn gather_range(&self, low: usize, high: usize, point: Point3<f64>, radius2: f64, found: &mut Vec<(f64, usize)>) {
+	// photons in [low, high) within the radius, pruning the side of the split that's too far
+	if low >= high {
+	    return;
+	}
+	let middle = low+(high-low)/2;
+	let photon = &self.photons[middle];
+	let distance2 = photon.position.distance2(point);
+	if distance2 <= radius2 {
+	    found.push((distance2, middle));
+	}
+	let axis = self.axes[middle];
+	let delta = PhotonMap::coordinate(&point, axis)-PhotonMap::coordinate(&photon.position, axis);
+	let (near, far) = if delta < 0.0 {((low, middle), (middle+1, high))} else {((middle+1, high), (low, middle))};
+	self.gather_range(near.0, near.1, point, radius2, found);
+	if delta*delta <= radius2 {
+	    self.gather_range(far.0, far.1, point, radius2, found);
+	}
+    }
+    pub fn irradiance(&self, point: Point3<f64>, normal: Vector3<f64>) -> Color {
+	// photon power arriving at the front of the surface over the disc holding the nearest photons
+	let mut found = Vec::new();
+	self.gather_range(0, self.photons.len(), point, self.radius*self.radius, &mut found);
+	if found.is_empty() {
+	    return consts::BLACK;
+	}
+	let radius2 = if found.len() > self.gather {
+	    found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	    found.truncate(self.gather);
+	    found[found.len()-1].0
+	} else {
+	    self.radius*self.radius
+	};
+	let mut power = consts::BLACK;
+	for &(_, i) in found.iter() {
+	    if self.photons[i].direction.dot(normal) < 0.0 {
+		power += self.photons[i].power;
+	    }
+	}
+	power/(std::f64::consts::PI*radius2.max(1e-12))
+    }
+}
@@ -4,6 +4,7 @@ use crate::color::*;
 #[allow(dead_code)]
 pub struct Pixvec { // two dimensional
     data: Vec<Vec<Color>>,
+    alpha: Vec<Vec<f64>>, // empty unless has_alpha
     pub colorspace: gdk_pixbuf::Colorspace,
     pub has_alpha: bool,
     pub bits_per_sample: i32,
@@ -18,7 +19,7 @@ impl Pixvec {
 	       height: usize) -> Pixvec {
 	let mut data = Vec::with_capacity(height as usize);
 	data.resize_with(height as usize, || vec![consts::BLACK; width as usize]);
-	Pixvec {data: data, colorspace: gdk_pixbuf::Colorspace::Rgb, has_alpha: false, bits_per_sample: 8, width: width, height: height, rowstride: 3*width}
+	Pixvec {data: data, alpha: Vec::new(), colorspace: gdk_pixbuf::Colorspace::Rgb, has_alpha: false, bits_per_sample: 8, width: width, height: height, rowstride: 3*width}
     }
     pub fn new_from_vec(mut data: Vec<Vec<Color>>,
 			colorspace: gdk_pixbuf::Colorspace,
@@ -35,10 +36,42 @@ impl Pixvec {
 	if data.len() < height as usize {
 	    data.resize_with(height as usize, || vec![consts::BLACK; width as usize]);
 	}
-	Pixvec {data: data, colorspace: colorspace, has_alpha: has_alpha, bits_per_sample: bits_per_sample, width: width, height: height, rowstride: rowstride}
+	let alpha = if has_alpha {vec![vec![1.0; width]; height]} else {Vec::new()}; // opaque until set
+	Pixvec {data: data, alpha: alpha, colorspace: colorspace, has_alpha: has_alpha, bits_per_sample: bits_per_sample, width: width, height: height, rowstride: rowstride}
     }
     pub fn copy(&self) -> Pixvec {
-	Pixvec::new_from_vec(self.data.to_vec(), self.colorspace, self.has_alpha, self.bits_per_sample, self.width, self.height, self.rowstride)
+	let mut copy = Pixvec::new_from_vec(self.data.to_vec(), self.colorspace, self.has_alpha, self.bits_per_sample, self.width, self.height, self.rowstride);
+	copy.alpha = self.alpha.to_vec();
+	copy
+    }
+    pub fn memory_usage(&self) -> usize { // bytes held by pixel data
+	self.width*self.height*std::mem::size_of::<Color>()
+	    + if self.has_alpha {self.width*self.height*std::mem::size_of::<f64>()} else {0}
+    }
+    pub fn get_alpha(&self, i: usize, j: usize) -> f64 { // fully opaque without an alpha channel
+	if self.has_alpha {self.alpha[i][j]} else {1.0}
+    }
+    pub fn set_alpha(&mut self, i: usize, j: usize, alpha: f64) {
+	if !self.has_alpha {
+	    self.alpha = vec![vec![1.0; self.width]; self.height];
+	    self.has_alpha = true;
+	    self.rowstride = 4*self.width;
+	}
+	self.alpha[i][j] = alpha;
+    }
+    pub fn alpha_to_pixvec(&self) -> Option<Pixvec> {
+	// alpha as a grey image, so it can be filtered like colour
+	if !self.has_alpha {
+	    return None;
+	}
+	let mut grey = Pixvec::new(self.width, self.height);
+	for i in 0..self.height {
+	    for j in 0..self.width {
+		let a = self.alpha[i][j];
+		grey[i][j] = Color::new(a, a, a);
+	    }
+	}
+	Some(grey)
     }
     pub fn iter(&self) -> std::slice::Iter<'_, Vec<Color>> {
 	self.data.iter()
@@ -54,45 +87,68 @@ impl Pixvec {
 // casting to/from Pixbuf
 impl From<&gdk_pixbuf::Pixbuf> for Pixvec {
     fn from(pbuf: &gdk_pixbuf::Pixbuf) -> Self {
-	if pbuf.get_has_alpha() {
-	    panic!("Pixelbuffer has Alpha, can't continue");
-	}
+	Pixvec::from_pixbuf(pbuf, true)
+    }
+}
+
+impl Pixvec {
+    pub fn from_pixbuf(pbuf: &gdk_pixbuf::Pixbuf, srgb: bool) -> Self {
+	// srgb images are converted to linear, others (normal maps, etc.) are taken as-is
 	if pbuf.get_colorspace() != gdk_pixbuf::Colorspace::Rgb {
 	    panic!("Pixelbuffer isn't in Rgb, can't continue");
 	} // assuming images come in as sRGB and need to be converted to linear
 	let bytes = pbuf.read_pixel_bytes().unwrap();
 	let height = pbuf.get_height() as usize;
 	let rowstride = pbuf.get_rowstride() as usize;
+	let channels = pbuf.get_n_channels() as usize; // 4 with alpha, alpha is never gamma encoded
+	let has_alpha = pbuf.get_has_alpha();
 	let mut data : Vec<Vec<Color>> = Vec::with_capacity(height);
+	let mut alpha : Vec<Vec<f64>> = Vec::new();
 	let width = pbuf.get_width() as usize;
-	let padding = rowstride%width;
 	for i in 0..height {
 	    let mut row : Vec<Color> = Vec::with_capacity(width);
-	    for j in (3*i*width+i*padding..3*(i+1)*width+(i+1)*padding-padding).step_by(3) {
-		row.push(Color::new_from_srgb(*bytes.get(j).unwrap(), *bytes.get(j+1).unwrap(), *bytes.get(j+2).unwrap()));
+	    let mut alpha_row : Vec<f64> = Vec::new();
+	    for j in (i*rowstride..i*rowstride+channels*width).step_by(channels) {
+		let (r, g, b) = (*bytes.get(j).unwrap(), *bytes.get(j+1).unwrap(), *bytes.get(j+2).unwrap());
+		row.push(if srgb {Color::new_from_srgb(r, g, b)} else {Color::new_from_linear(r, g, b)});
+		if has_alpha {
+		    alpha_row.push((*bytes.get(j+3).unwrap() as f64)/255.0);
+		}
 	    }
 	    data.push(row);
+	    if has_alpha {
+		alpha.push(alpha_row);
+	    }
 	}
-	Pixvec::new_from_vec(data,
-		    pbuf.get_colorspace(),
-		    pbuf.get_has_alpha(),
-		    pbuf.get_bits_per_sample(), 
-		    width,
-		    height,
-		    rowstride-padding)
+	let mut pvec = Pixvec::new_from_vec(data,
+					    pbuf.get_colorspace(),
+					    has_alpha,
+					    pbuf.get_bits_per_sample(), 
+					    width,
+					    height,
+					    channels*width);
+	pvec.alpha = alpha;
+	pvec
     }
 }
 
 impl From<&mut Pixvec> for gdk_pixbuf::Pixbuf {
     fn from(pvec: &mut Pixvec) -> Self {
-	let mut bytes : Vec<u8> = Vec::with_capacity(pvec.height*pvec.width*3);
-	for row in pvec.iter_mut() {
-	    for rgb in row.iter_mut() {
+	let channels = if pvec.has_alpha {4} else {3};
+	let mut bytes : Vec<u8> = Vec::with_capacity(pvec.height*pvec.width*channels);
+	let (height, width) = (pvec.height, pvec.width);
+	for i in 0..height {
+	    for j in 0..width {
+		let alpha = pvec.get_alpha(i, j);
+		let rgb = &mut pvec[i][j];
 		rgb.clamp();
 		let gamma_corrected_rgb = rgb.linear_to_srgb();
 		bytes.push(gamma_corrected_rgb.0);
 		bytes.push(gamma_corrected_rgb.1);
 		bytes.push(gamma_corrected_rgb.2);
+		if channels == 4 {
+		    bytes.push((alpha.max(0.0).min(1.0)*255.0) as u8);
+		}
 	    }
 	}
 	gdk_pixbuf::Pixbuf::new_from_bytes(&glib::Bytes::from(&bytes), pvec.colorspace, pvec.has_alpha, pvec.bits_per_sample, pvec.width as i32, pvec.height as i32, pvec.rowstride as i32)
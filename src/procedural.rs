@@ -0,0 +1,170 @@
+use cgmath::Point3;
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::cgmath::EuclideanSpace;
+use crate::color::*;
+use crate::texture::UvTransform;
+
+pub struct Perlin { // improved perlin noise over a seeded permutation table
+    perm: Vec<usize>
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+	let mut perm: Vec<usize> = (0..256).collect();
+	let mut state = seed ^ 0x9E3779B97F4A7C15;
+	for i in (1..256).rev() { // fisher-yates with xorshift
+	    state ^= state << 13;
+	    state ^= state >> 7;
+	    state ^= state << 17;
+	    perm.swap(i, (state % (i as u64 + 1)) as usize);
+	}
+	let repeat = perm.clone();
+	perm.extend(repeat);
+	Perlin{perm: perm}
+    }
+    fn fade(t: f64) -> f64 {
+	t*t*t*(t*(t*6.0-15.0)+10.0)
+    }
+    fn lerp(t: f64, a: f64, b: f64) -> f64 {
+	a+t*(b-a)
+    }
+    fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+	let h = hash & 15;
+	let u = if h < 8 {x} else {y};
+	let v = if h < 4 {y} else if h == 12 || h == 14 {x} else {z};
+	(if h & 1 == 0 {u} else {-u}) + (if h & 2 == 0 {v} else {-v})
+    }
+    pub fn noise(&self, p: Point3<f64>) -> f64 { // roughly in [-1, 1]
+	let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+	let (xi, yi, zi) = ((fx as i64 & 255) as usize, (fy as i64 & 255) as usize, (fz as i64 & 255) as usize);
+	let (x, y, z) = (p.x-fx, p.y-fy, p.z-fz);
+	let (u, v, w) = (Perlin::fade(x), Perlin::fade(y), Perlin::fade(z));
+	let perm = &self.perm;
+	let a  = perm[xi]+yi;
+	let aa = perm[a]+zi;
+	let ab = perm[a+1]+zi;
+	let b  = perm[xi+1]+yi;
+	let ba = perm[b]+zi;
+	let bb = perm[b+1]+zi;
+	Perlin::lerp(w,
+		     Perlin::lerp(v,
+				  Perlin::lerp(u, Perlin::grad(perm[aa], x, y, z), Perlin::grad(perm[ba], x-1.0, y, z)),
+				  Perlin::lerp(u, Perlin::grad(perm[ab], x, y-1.0, z), Perlin::grad(perm[bb], x-1.0, y-1.0, z))),
+		     Perlin::lerp(v,
+				  Perlin::lerp(u, Perlin::grad(perm[aa+1], x, y, z-1.0), Perlin::grad(perm[ba+1], x-1.0, y, z-1.0)),
+				  Perlin::lerp(u, Perlin::grad(perm[ab+1], x, y-1.0, z-1.0), Perlin::grad(perm[bb+1], x-1.0, y-1.0, z-1.0))))
+    }
+    pub fn fbm(&self, p: Point3<f64>, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+	// fractal sum of octaves, normalized back to roughly [-1, 1]
+	let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+	for _ in 0..octaves {
+	    sum += amplitude*self.noise(Point3::from_vec(p.to_vec()*frequency));
+	    total += amplitude;
+	    amplitude *= gain;
+	    frequency *= lacunarity;
+	}
+	if total > 0.0 {sum/total} else {0.0}
+    }
+    pub fn turbulence(&self, p: Point3<f64>, octaves: u32) -> f64 { // in [0, 1]
+	let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+	for _ in 0..octaves {
+	    sum += amplitude*self.noise(Point3::from_vec(p.to_vec()*frequency)).abs();
+	    total += amplitude;
+	    amplitude *= 0.5;
+	    frequency *= 2.0;
+	}
+	if total > 0.0 {(sum/total).min(1.0)} else {0.0}
+    }
+}
+
+
+pub struct ColorRamp { // piecewise linear colour gradient over [0, 1]
+    stops: Vec<(f64, Color)>
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(f64, Color)>) -> Self {
+	stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	ColorRamp{stops: stops}
+    }
+    pub fn new_two(from: Color, to: Color) -> Self {
+	ColorRamp::new(vec![(0.0, from), (1.0, to)])
+    }
+    pub fn get_color(&self, t: f64) -> Color {
+	if self.stops.is_empty() {
+	    return consts::BLACK;
+	}
+	if t <= self.stops[0].0 {
+	    return self.stops[0].1;
+	}
+	for pair in self.stops.windows(2) {
+	    let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+	    if t <= t1 {
+		let f = if t1 > t0 {(t-t0)/(t1-t0)} else {1.0};
+		return c0*(1.0-f) + c1*f;
+	    }
+	}
+	self.stops[self.stops.len()-1].1
+    }
+}
+
+
+pub enum Pattern {
+    Noise,
+    Fbm{octaves: u32, lacunarity: f64, gain: f64},
+    Turbulence{octaves: u32},
+    Marble{frequency: f64, distortion: f64, octaves: u32},  // veins along x
+    Wood{frequency: f64, distortion: f64, octaves: u32},    // rings around z
+    Checker{size: f64},
+    Gradient{direction: Vector3<f64>}                       // 0 at origin to 1 one unit along direction
+}
+
+pub enum TextureSpace {
+    Object, // 3D position relative to the object origin
+    Uv      // the primitive's 2D texture coordinates
+}
+
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub ramp: ColorRamp,
+    pub scale: f64,
+    pub space: TextureSpace,
+    pub transform: UvTransform, // only used in uv space
+    noise: Perlin
+}
+
+impl Procedural {
+    pub fn new(pattern: Pattern, ramp: ColorRamp, scale: f64, space: TextureSpace, seed: u64) -> Self {
+	Procedural{pattern: pattern, ramp: ramp, scale: scale, space: space, transform: UvTransform::IDENTITY, noise: Perlin::new(seed)}
+    }
+    fn evaluate(&self, p: Point3<f64>) -> f64 { // pattern value in [0, 1]
+	match self.pattern {
+	    Pattern::Noise => self.noise.noise(p)*0.5+0.5,
+	    Pattern::Fbm{octaves, lacunarity, gain} => self.noise.fbm(p, octaves, lacunarity, gain)*0.5+0.5,
+	    Pattern::Turbulence{octaves} => self.noise.turbulence(p, octaves),
+	    Pattern::Marble{frequency, distortion, octaves} => {
+		((p.x*frequency + distortion*self.noise.turbulence(p, octaves)).sin())*0.5+0.5
+	    },
+	    Pattern::Wood{frequency, distortion, octaves} => {
+		let rings = (p.x*p.x+p.y*p.y).sqrt()*frequency + distortion*self.noise.turbulence(p, octaves);
+		rings-rings.floor()
+	    },
+	    Pattern::Checker{size} => {
+		let parity = (p.x/size).floor() as i64 + (p.y/size).floor() as i64 + (p.z/size).floor() as i64;
+		if parity.rem_euclid(2) == 0 {0.0} else {1.0}
+	    },
+	    Pattern::Gradient{direction} => p.to_vec().dot(direction).max(0.0).min(1.0)
+	}
+    }
+    pub fn get_color(&self, object_point: Vector3<f64>, uv: (f64, f64)) -> Color {
+	let p = match self.space {
+	    TextureSpace::Object => Point3::from_vec(object_point),
+	    TextureSpace::Uv => {
+		let (u, v) = self.transform.apply(uv.0, uv.1);
+		Point3{x: u, y: v, z: 0.0}
+	    }
+	};
+	self.ramp.get_color(self.evaluate(Point3::from_vec(p.to_vec()*self.scale)))
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::{Error, ErrorKind, Result};
+use crate::color::*;
+use crate::pixvec::*;
+
+// Radiance .hdr (RGBE) reader, pixels come out as unclamped linear floats
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Radiance HDR: {}", message))
+}
+
+fn rgbe_to_color(rgbe: &[u8]) -> Color {
+    if rgbe[3] == 0 {
+	return consts::BLACK;
+    }
+    let f = 2f64.powi(rgbe[3] as i32 - (128+8));
+    Color::new(rgbe[0] as f64*f, rgbe[1] as f64*f, rgbe[2] as f64*f)
+}
+
+fn read_line(bytes: &[u8], pos: &mut usize) -> Result<String> {
+    let start = *pos;
+    while *pos < bytes.len() && bytes[*pos] != b'\n' {
+	*pos += 1;
+    }
+    if *pos >= bytes.len() {
+	return Err(invalid("unexpected end of header"));
+    }
+    *pos += 1; // skip newline
+    Ok(String::from_utf8_lossy(&bytes[start..*pos-1]).to_string())
+}
+
+fn read_scanline(bytes: &[u8], pos: &mut usize, width: usize) -> Result<Vec<u8>> {
+    // returns width*4 bytes of rgbe, either run length encoded or flat
+    let mut scanline = vec![0u8; width*4];
+    if *pos+4 > bytes.len() {
+	return Err(invalid("truncated pixel data"));
+    }
+    let header = &bytes[*pos..*pos+4];
+    let rle = width >= 8 && width < 32768 && header[0] == 2 && header[1] == 2 && header[2] & 0x80 == 0;
+    if !rle {
+	let end = *pos+width*4;
+	if end > bytes.len() {
+	    return Err(invalid("truncated pixel data"));
+	}
+	scanline.copy_from_slice(&bytes[*pos..end]);
+	*pos = end;
+	return Ok(scanline);
+    }
+    if ((header[2] as usize) << 8 | header[3] as usize) != width {
+	return Err(invalid("scanline width mismatch"));
+    }
+    *pos += 4;
+    for channel in 0..4 { // each channel is stored separately
+	let mut x = 0;
+	while x < width {
+	    let count = *bytes.get(*pos).ok_or_else(|| invalid("truncated pixel data"))? as usize;
+	    *pos += 1;
+	    if count > 128 { // run
+		let run = count-128;
+		let value = *bytes.get(*pos).ok_or_else(|| invalid("truncated pixel data"))?;
+		*pos += 1;
+		if run == 0 || x+run > width {
+		    return Err(invalid("bad run length"));
+		}
+		for _ in 0..run {
+		    scanline[x*4+channel] = value;
+		    x += 1;
+		}
+	    } else { // literal
+		if count == 0 || x+count > width || *pos+count > bytes.len() {
+		    return Err(invalid("bad literal length"));
+		}
+		for i in 0..count {
+		    scanline[x*4+channel] = bytes[*pos+i];
+		    x += 1;
+		}
+		*pos += count;
+	    }
+	}
+    }
+    Ok(scanline)
+}
+
+pub fn read(bytes: &[u8]) -> Result<Pixvec> {
+    let mut pos = 0;
+    let magic = read_line(bytes, &mut pos)?;
+    if !magic.starts_with("#?") {
+	return Err(invalid("missing #? signature"));
+    }
+    loop { // header ends with an empty line
+	let line = read_line(bytes, &mut pos)?;
+	if line.is_empty() {
+	    break;
+	}
+	if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+	    return Err(invalid("only 32-bit_rle_rgbe is supported"));
+	}
+    }
+    let resolution = read_line(bytes, &mut pos)?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+	return Err(invalid("only -Y height +X width orientation is supported"));
+    }
+    let height: usize = fields[1].parse().map_err(|_| invalid("bad height"))?;
+    let width: usize = fields[3].parse().map_err(|_| invalid("bad width"))?;
+    match width.checked_mul(height) {
+	Some(pixels) if pixels > 0 && pixels <= 1 << 28 => (), // a quarter billion pixels is plenty
+	_ => return Err(invalid("bad resolution"))
+    }
+
+    let mut pvec = Pixvec::new(width, height);
+    for i in 0..height {
+	let scanline = read_scanline(bytes, &mut pos, width)?;
+	for j in 0..width {
+	    pvec[i][j] = rgbe_to_color(&scanline[j*4..j*4+4]);
+	}
+    }
+    Ok(pvec)
+}
+
+pub fn read_file(uri: &str) -> Result<Pixvec> {
+    read(&std::fs::read(uri)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(resolution: &str, pixels: &[u8]) -> Vec<u8> {
+	let mut bytes = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes();
+	bytes.extend_from_slice(pixels);
+	bytes
+    }
+
+    #[test]
+    fn reads_flat_pixels() {
+	let pvec = read(&file("-Y 1 +X 2", &[128, 64, 0, 129, 0, 0, 0, 0])).unwrap();
+	assert_eq!((pvec.width, pvec.height), (2, 1));
+	assert_eq!(pvec[0][0].luminance(), Color::new(1.0, 0.5, 0.0).luminance());
+	assert_eq!(pvec[0][1].luminance(), 0.0);
+    }
+
+    #[test]
+    fn rejects_huge_resolutions() {
+	for resolution in &["-Y 4611686018427387904 +X 8", "-Y 1 +X 18446744073709551615", "-Y 65536 +X 65536", "-Y 0 +X 8"] {
+	    let error = read(&file(resolution, &[])).err().unwrap();
+	    assert_eq!(error.kind(), ErrorKind::InvalidData);
+	}
+    }
+
+    #[test]
+    fn rejects_truncated_pixels() {
+	assert!(read(&file("-Y 2 +X 2", &[128, 64, 0, 129])).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::cell::Cell;
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+
+thread_local! {
+    static SEED: Cell<u64> = Cell::new(0x9E3779B97F4A7C15);
+}
+
+pub fn random() -> f64 { // uniform in [0, 1), xorshift64*
+    SEED.with(|seed| {
+	let mut x = seed.get();
+	x ^= x >> 12;
+	x ^= x << 25;
+	x ^= x >> 27;
+	seed.set(x);
+	(x.wrapping_mul(0x2545F4914F6CDD1D) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+pub fn orthonormal_basis(normal: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    // two unit vectors perpendicular to normal and to each other
+    let helper = if normal.x.abs() > 0.9 {
+	Vector3{x: 0.0, y: 1.0, z: 0.0}
+    } else {
+	Vector3{x: 1.0, y: 0.0, z: 0.0}
+    };
+    let tangent = normal.cross(helper).normalize();
+    let bitangent = normal.cross(tangent);
+    (tangent, bitangent)
+}
+
+pub fn stratified(samples: u32) -> Vec<(f64, f64)> {
+    // jittered points in [0, 1)^2, one per row and one per column of a samples x samples grid
+    // (latin hypercube), so any count covers the square evenly
+    let n = samples as usize;
+    let mut columns: Vec<usize> = (0..n).collect();
+    for i in (1..n).rev() { // shuffle which column each row gets
+	let j = ((random()*((i+1) as f64)) as usize).min(i);
+	columns.swap(i, j);
+    }
+    columns.iter().enumerate()
+	.map(|(row, &column)| ((column as f64 + random())/(n as f64), (row as f64 + random())/(n as f64)))
+	.collect()
+}
+
+pub fn cosine_hemisphere(normal: Vector3<f64>) -> Vector3<f64> {
+    // direction around normal with probability proportional to cos(theta)
+    let (u, v) = (random(), random());
+    let r = u.sqrt();
+    let phi = 2.0*std::f64::consts::PI*v;
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (tangent*r*phi.cos() + bitangent*r*phi.sin() + normal*(1.0-u).max(0.0).sqrt()).normalize()
+}
+
+pub fn cone(axis: Vector3<f64>, cos_max: f64, s: f64, t: f64) -> Vector3<f64> {
+    // uniform direction within `cos_max` of `axis`, for (s, t) in [0, 1)
+    let cos_theta = 1.0 - s*(1.0-cos_max);
+    let sin_theta = (1.0-cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0*std::f64::consts::PI*t;
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    (tangent*sin_theta*phi.cos() + bitangent*sin_theta*phi.sin() + axis*cos_theta).normalize()
+}
+
+pub fn ggx_microfacet(normal: Vector3<f64>, alpha: f64) -> Vector3<f64> {
+    // microfacet normal distributed by the GGX (Trowbridge-Reitz) distribution
+    let (u, v) = (random(), random());
+    let theta = (alpha*(u/(1.0-u)).sqrt()).atan();
+    let phi = 2.0*std::f64::consts::PI*v;
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (tangent*theta.sin()*phi.cos() + bitangent*theta.sin()*phi.sin() + normal*theta.cos()).normalize()
+}
@@ -1,78 +1,181 @@
 use crate::color::*;
-use crate::pixvec::*;
 use crate::camera_math::Camera;
+use crate::sampling;
+use crate::outline::Outline;
+use crate::conductor::Conductor;
+use crate::texture::*;
+use crate::environment::Background;
+use crate::lights::*;
+use crate::photon::PhotonMap;
 use crate::cgmath::InnerSpace;
-use crate::cgmath::MetricSpace;
 use cgmath::Point3;
 use cgmath::Vector3;
 
 static RECURSION_DEPTH : i32 = 35;
 static NORMAL_BIAS     : f64 = 1e-13; // used for shadow ache and such
+static PASS_BIAS       : f64 = 1e-9;  // step past a transparent hit along the ray
 
-pub struct ImageMap {
-    pub pixvec: Pixvec,
-    pub scale: f64
+pub fn fresnel(dp: f64, eta_i: f64, eta_t: f64) -> f64 {
+    // unpolarized dielectric fresnel, dp is the cosine between incident and normal
+    let sin_t = eta_i / eta_t * (1.0 - dp * dp).max(0.0).sqrt();
+    if sin_t > 1.0 {
+        1.0 // total internal reflection
+    } else {
+        let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+        let cos_i = dp.abs();
+        let r_s = ((eta_t * cos_i) - (eta_i * cos_t)) / ((eta_t * cos_i) + (eta_i * cos_t));
+        let r_p = ((eta_i * cos_i) - (eta_t * cos_t)) / ((eta_i * cos_i) + (eta_t * cos_t));
+        (r_s * r_s + r_p * r_p) / 2.0
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Luminance
+}
 
-impl ImageMap {
-    pub fn new_from_file(uri: String, scale: f64) -> Self {
-	ImageMap{pixvec: Pixvec::from(&gdk_pixbuf::Pixbuf::new_from_file(uri).unwrap()), scale: scale}
+impl Channel {
+    pub fn pick(&self, color: Color) -> f64 {
+	let (red, green, blue) = color.channels();
+	match *self {
+	    Channel::Red => red,
+	    Channel::Green => green,
+	    Channel::Blue => blue,
+	    Channel::Luminance => color.luminance()
+	}
     }
 }
 
+pub enum Param { // a scalar material input, fixed or painted with a texture
+    Constant(f64),
+    Texture{texture: Texture, channel: Channel, min: f64, max: f64} // channel 0..1 maps to min..max
+}
 
-pub enum Texture {
-    Color(Color),
-    ImageMap(ImageMap)
+impl Param {
+    pub fn get(&self, obj: &SceneObject, location: &Point3<f64>, footprint: Footprint) -> f64 {
+	match *self {
+	    Param::Constant(value) => value,
+	    Param::Texture{ref texture, channel, min, max} => {
+		min + (max-min)*channel.pick(obj.sample_texture(Some(texture), location, footprint))
+	    }
+	}
+    }
+}
+
+impl From<f64> for Param {
+    fn from(value: f64) -> Self {
+	Param::Constant(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSampling { // how diffuse shading combines the scene's lights
+    Average,             // legacy: the sum divided by the number of lights, so each added light dims the rest
+    Sum,                 // every light adds its full contribution
+    Stochastic(u32)      // this many lights per point, picked by estimated contribution, for scenes with many lights
 }
 
 pub struct ShadeDiffuse {
-    strength: f64
+    strength: Param
 }
 
 impl ShadeDiffuse{
-    pub fn new(strength: f64) -> Self {
-	ShadeDiffuse{strength: strength}
+    pub fn new(strength: impl Into<Param>) -> Self {
+	ShadeDiffuse{strength: strength.into()}
     }
-    pub fn shade_diffuse(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject) -> Color {
+    fn light_contribution(scene: &Scene, light: &SceneLight, origin: Point3<f64>, surface_normal: Vector3<f64>, receives_shadows: bool) -> Color {
+	// irradiance arriving from one light, including its shadows
 	let mut mix = consts::BLACK;
-	let new_origin = location+surface_normal*NORMAL_BIAS;
-	for light in scene.lights.iter() {
-	    let dir_to_light = light.get_direction(new_origin);
-	    let dist_to_light = light.dist_to(new_origin);
-	    let shadow_ray = Ray{origin: new_origin, direction: dir_to_light};
-
-	    if !shadow_ray.any_intersect(scene, dist_to_light) {
-		let dp = surface_normal.dot(dir_to_light);
-		let power =  light.get_apparent_intensity(dp, dist_to_light)
-		    * obj.get_albedo();
+	for sample in light.get_samples(origin) { // several for area lights
+	    let shadow_ray = Ray{origin: origin, direction: sample.direction};
+	    let shadow = if receives_shadows {shadow_ray.transmittance(scene, sample.distance, Some(light))} else {consts::WHITE};
 
-		let color = (obj.get_texture_color(&new_origin))
-		    * (*light.get_color()) * power;
+	    if shadow.luminance() > 0.0 {
+		let dp = surface_normal.dot(sample.direction);
+		let power =  light.get_apparent_intensity(dp, sample.direction, sample.distance)
+		    * sample.weight;
 
-		mix += color;
+		mix += (*light.get_color()) * sample.tint * shadow * power;
 	    }
 	}
-	mix = mix/(scene.lights.len() as f64); // take average
-	mix*self.strength
+	mix
+    }
+    fn estimate(light: &SceneLight, origin: Point3<f64>, surface_normal: Vector3<f64>) -> f64 {
+	// unshadowed brightness from the light's centre, to pick lights by
+	let direction = light.get_direction(origin);
+	light.get_apparent_intensity(surface_normal.dot(direction).max(0.0), direction, light.dist_to(origin))
+	    * light.get_color().luminance()
+    }
+    pub fn shade_diffuse(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject, footprint: Footprint) -> Color {
+	let mut mix = consts::BLACK;
+	let new_origin = location+surface_normal*NORMAL_BIAS;
+	let albedo = obj.get_albedo(&new_origin, footprint);
+	let surface_color = obj.get_texture_color(&new_origin, footprint);
+	let index = scene.object_index(obj);
+	let receives_shadows = obj.get_visibility().receives_shadows;
+	let lights = scene.lights.iter().filter(|light| light.illuminates(index));
+	match scene.light_sampling {
+	    LightSampling::Average | LightSampling::Sum => {
+		for light in lights {
+		    mix += ShadeDiffuse::light_contribution(scene, light, new_origin, surface_normal, receives_shadows);
+		}
+		if scene.light_sampling == LightSampling::Average {
+		    mix = mix/(scene.lights.len() as f64); // take average
+		}
+	    },
+	    LightSampling::Stochastic(count) => {
+		let weighted: Vec<(&SceneLight, f64)> = lights
+		    .map(|light| (light, ShadeDiffuse::estimate(light, new_origin, surface_normal)))
+		    .filter(|&(_, estimate)| estimate > 0.0)
+		    .collect();
+		let total: f64 = weighted.iter().map(|&(_, estimate)| estimate).sum();
+		let count = count.max(1);
+		for _ in 0..count {
+		    let mut pick = sampling::random()*total;
+		    for &(light, estimate) in weighted.iter() {
+			if pick < estimate {
+			    // divide by the chance of picking it, so the expected value is the full sum
+			    let probability = estimate/total*(count as f64);
+			    mix += ShadeDiffuse::light_contribution(scene, light, new_origin, surface_normal, receives_shadows)/probability;
+			    break;
+			}
+			pick -= estimate;
+		    }
+		}
+	    }
+	}
+	if let Some(ref caustics) = scene.caustics {
+	    mix += caustics.irradiance(new_origin, surface_normal);
+	}
+	surface_color*mix*albedo*self.strength.get(obj, &location, footprint)
     }
 }
 
 pub struct ShadeReflect {
-    strength: f64
+    strength: Param,
+    conductor: Option<Conductor> // tints reflections by angle, None reflects untinted
 }
 
 impl ShadeReflect {
-    pub fn new(strength: f64) -> Self {
-	ShadeReflect{strength: strength}
+    pub fn new(strength: impl Into<Param>) -> Self {
+	ShadeReflect{strength: strength.into(), conductor: None}
     }
-    pub fn shade_reflect(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, _obj: &SceneObject, n_th: i32) -> Color {
-	if n_th < RECURSION_DEPTH {
+    pub fn new_metal(strength: impl Into<Param>, conductor: Conductor) -> Self {
+	ShadeReflect{strength: strength.into(), conductor: Some(conductor)}
+    }
+    pub fn shade_reflect(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject, footprint: Footprint, n_th: i32) -> Color {
+	let strength = self.strength.get(obj, &location, footprint);
+	if n_th < RECURSION_DEPTH && strength > 0.0 {
 	    let reflection_vector = (incident-2.0*incident.dot(surface_normal)*surface_normal).normalize();
 	    let reflection_ray = Ray{origin: location+surface_normal*NORMAL_BIAS, direction: reflection_vector};
-	    if let Some((color, _power)) = reflection_ray.trace(scene, n_th+1) {
-		color*self.strength
+	    if let Some((color, _power)) = reflection_ray.trace_cone(scene, n_th+1, footprint.width(), RayKind::Reflection) {
+		match self.conductor {
+		    Some(ref c) => color*c.fresnel(incident.dot(surface_normal))*strength,
+		    None => color*strength
+		}
 	    } else {
 		consts::BLACK
 	    }
@@ -83,120 +186,377 @@ impl ShadeReflect {
 }
 
 pub struct ShadeRefract {
-    strength: f64,
-    index: f64
+    strength: Param,
+    index: Param,
+    roughness: Param, // 0.0 is clear glass, higher values frost the transmission
+    samples: u32
 }
 
 impl ShadeRefract {
-    pub fn new(strength: f64, index: f64) -> Self {
-	ShadeRefract{strength: strength, index: index}
+    pub fn new(strength: impl Into<Param>, index: impl Into<Param>) -> Self {
+	ShadeRefract{strength: strength.into(), index: index.into(), roughness: Param::Constant(0.0), samples: 1}
+    }
+    pub fn new_rough(strength: impl Into<Param>, index: impl Into<Param>, roughness: impl Into<Param>, samples: u32) -> Self {
+	ShadeRefract{strength: strength.into(), index: index.into(), roughness: roughness.into(), samples: samples.max(1)}
     }
-    fn refract_only(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, n_th: i32, dp: f64, eta: f64) -> Color {
+    pub fn refract_direction(incident: Vector3<f64>, facing_normal: Vector3<f64>, eta: f64) -> Option<Vector3<f64>> {
+	// facing_normal points back towards the incident ray
+	let ref_dp = -incident.dot(facing_normal);
+	let dist2 = 1.0 - eta.powi(2) * (1.0 - ref_dp.powi(2)); // direction of refraction
+	if dist2 > 0.0 { // else wrong direction -- ignore
+	    Some((incident + ref_dp*facing_normal)*eta - facing_normal*dist2.sqrt())
+	} else {
+	    None
+	}
+    }
+    fn refract_only(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, cone: f64, n_th: i32, dp: f64, eta: f64, roughness: f64) -> Color {
 	// refract only (no fresnel)	
 	if n_th < RECURSION_DEPTH { // else overflow
-	    let ref_dp = if dp < 0.0 {-dp} else {dp}; // correct based on inside or outside
-	    let ref_n = if dp < 0.0 {surface_normal} else {-surface_normal};
-	    
-	    let dist2 = 1.0 - eta.powi(2) * (1.0 - ref_dp.powi(2)); // direction of refraction
-	    if dist2 > 0.0 { // else wrong direction -- ignore
-		if let Some((color, _power)) = (Ray{ // trace refraction
-		    origin: location - ref_n*NORMAL_BIAS,
-		    direction: (incident + ref_dp*ref_n)*eta - ref_n*dist2.sqrt(),
-		}).trace(scene, n_th+1) { // else no collision after refraction
-		    return color;
+	    let ref_n = if dp < 0.0 {surface_normal} else {-surface_normal}; // correct based on inside or outside
+	    let origin = location - ref_n*NORMAL_BIAS;
+
+	    if roughness <= 0.0 {
+		if let Some(direction) = ShadeRefract::refract_direction(incident, ref_n, eta) {
+		    if let Some((color, _power)) = (Ray{origin: origin, direction: direction}).trace_cone(scene, n_th+1, cone, RayKind::Refraction) {
+			return color;
+		    } // else no collision after refraction
+		}
+	    } else {
+		// only split into several rays near the camera, deeper bounces take one each
+		let samples = if n_th > 1 {1} else {self.samples};
+		let alpha = roughness*roughness;
+		let mut mix = consts::BLACK;
+		let mut accepted = 0; // rejected facets don't count, or frosting would darken the glass
+		for _ in 0..samples {
+		    let microfacet = sampling::ggx_microfacet(ref_n, alpha);
+		    if microfacet.dot(incident) >= 0.0 {
+			continue; // facet faces away from the ray
+		    }
+		    if let Some(direction) = ShadeRefract::refract_direction(incident, microfacet, eta) {
+			if direction.dot(ref_n) >= 0.0 {
+			    continue; // perturbed ray would leave through the same side
+			}
+			accepted += 1;
+			if let Some((color, _power)) = (Ray{origin: origin, direction: direction.normalize()}).trace_cone(scene, n_th+1, cone, RayKind::Refraction) {
+			    mix += color;
+			}
+		    }
+		}
+		if accepted > 0 {
+		    return mix/(accepted as f64);
+		}
+		// every facet was rejected, fall back to the smooth surface
+		if let Some(direction) = ShadeRefract::refract_direction(incident, ref_n, eta) {
+		    if let Some((color, _power)) = (Ray{origin: origin, direction: direction}).trace_cone(scene, n_th+1, cone, RayKind::Refraction) {
+			return color;
+		    }
 		}
 	    }
 	}
 	consts::BLACK
     }
-    fn fresnel(&self, dp: f64, eta_i: f64, eta_t: f64) -> f64 {
-	let sin_t = eta_i / eta_t * (1.0 - dp * dp).max(0.0).sqrt();
-	if sin_t > 1.0 {
-            1.0
-	} else {
-            let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
-            let cos_i = cos_t.abs();
-            let r_s = ((eta_t * cos_i) - (eta_i * cos_t)) / ((eta_t * cos_i) + (eta_i * cos_t));
-            let r_p = ((eta_i * cos_i) - (eta_t * cos_t)) / ((eta_i * cos_i) + (eta_t * cos_t));
-            (r_s * r_s + r_p * r_p) / 2.0
-	}
-    }
-    pub fn shade_refract(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject, n_th: i32) -> Color {
+    pub fn shade_refract(&self, scene: &Scene, location: Point3<f64>, incident: Vector3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject, footprint: Footprint, n_th: i32) -> Color {
 	// refraction + fresnel
 	let dp = incident.dot(surface_normal);
-	let eta_i = if dp < 0.0 {1.0} else {self.index};
-	let eta_t = if dp < 0.0 {self.index} else {1.0};
+	let index = self.index.get(obj, &location, footprint);
+	let eta_i = if dp < 0.0 {1.0} else {index};
+	let eta_t = if dp < 0.0 {index} else {1.0};
 	
-        let kr = self.fresnel(dp, eta_i, eta_t);
-        let surface_color = obj.get_texture_color(&location);
+        let kr = fresnel(dp, eta_i, eta_t);
+        let surface_color = obj.get_texture_color(&location, footprint);
         let refraction_color = if kr < 1.0 {
-	    self.refract_only(scene, location, incident, surface_normal, n_th, dp, eta_i/eta_t) * (1.0 - kr)
+	    let roughness = self.roughness.get(obj, &location, footprint);
+	    self.refract_only(scene, location, incident, surface_normal, footprint.width(), n_th, dp, eta_i/eta_t, roughness) * (1.0 - kr)
 	} else {
 	    consts::BLACK
 	};
 
-	let reflection_color = (ShadeReflect{strength: kr}).shade_reflect(scene, location, incident, surface_normal, obj, n_th);
+	let reflection_color = ShadeReflect::new(kr).shade_reflect(scene, location, incident, surface_normal, obj, footprint, n_th);
+
+        (reflection_color+refraction_color)*self.strength.get(obj, &location, footprint)*surface_color
+    }
+}
+
+pub struct ShadeToon { // cel shading, diffuse lighting quantised into flat bands
+    strength: f64,
+    ramp: Vec<Color> // one colour per band, darkest first
+}
+
+impl ShadeToon {
+    pub fn new(strength: f64, ramp: Vec<Color>) -> Self {
+	ShadeToon{strength: strength, ramp: ramp}
+    }
+    pub fn shade_toon(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, obj: &SceneObject, footprint: Footprint) -> Color {
+	if self.ramp.is_empty() {
+	    return consts::BLACK;
+	}
+	let new_origin = location+surface_normal*NORMAL_BIAS;
+	let mut lit: f64 = 0.0; // strongest unshadowed facing ratio
+	let index = scene.object_index(obj);
+	let receives_shadows = obj.get_visibility().receives_shadows;
+	for light in scene.lights.iter().filter(|light| light.illuminates(index)) {
+	    let dir_to_light = light.get_direction(new_origin);
+	    let dist_to_light = light.dist_to(new_origin);
+	    if light.get_apparent_intensity(1.0, dir_to_light, dist_to_light) <= 0.0 {
+		continue; // outside a spot's cone
+	    }
+	    let shadow_ray = Ray{origin: new_origin, direction: dir_to_light};
+	    let shadow = if receives_shadows {shadow_ray.transmittance(scene, dist_to_light, None).luminance()} else {1.0};
+	    lit = lit.max(surface_normal.dot(dir_to_light)*shadow);
+	}
+	let band = ((lit.max(0.0)*(self.ramp.len() as f64)) as usize).min(self.ramp.len()-1);
+	obj.get_texture_color(&new_origin, footprint) * self.ramp[band] * self.strength
+    }
+}
+
+pub struct ShadeAmbientOcclusion { // ambient term darkened by nearby geometry
+    strength: f64,
+    color: Color,
+    samples: u32,
+    radius: f64
+}
+
+impl ShadeAmbientOcclusion {
+    pub fn new(strength: f64, color: Color, samples: u32, radius: f64) -> Self {
+	ShadeAmbientOcclusion{strength: strength, color: color, samples: samples.max(1), radius: radius}
+    }
+    pub fn shade_ambient_occlusion(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, footprint: Footprint) -> Color {
+	// hemisphere faces the side the ray came from
+	let facing_normal = if incident.dot(surface_normal) > 0.0 {-surface_normal} else {surface_normal};
+	let new_origin = location+facing_normal*NORMAL_BIAS;
+	let mut unoccluded = 0;
+	for _ in 0..self.samples {
+	    let occlusion_ray = Ray{origin: new_origin, direction: sampling::cosine_hemisphere(facing_normal)};
+	    if !occlusion_ray.any_intersect(scene, self.radius) {
+		unoccluded += 1;
+	    }
+	}
+	let visibility = (unoccluded as f64)/(self.samples as f64);
+	obj.get_texture_color(&new_origin, footprint) * self.color * (obj.get_albedo(&new_origin, footprint) * visibility * self.strength)
+    }
+}
+
+pub enum MixFactor {
+    Constant(f64),    // fixed weight of the second node
+    Texture(Texture), // weight taken from texture brightness at the hit point
+    Fresnel(f64),     // dielectric fresnel reflectance for a given index
+    Facing(f64)       // 1-cos(angle) raised to a falloff exponent
+}
+
+impl MixFactor {
+    pub fn get_factor(&self, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, footprint: Footprint) -> f64 {
+	let factor = match *self {
+	    MixFactor::Constant(f) => f,
+	    MixFactor::Texture(ref t) => obj.sample_texture(Some(t), &location, footprint).luminance(),
+	    MixFactor::Fresnel(index) => {
+		let dp = incident.dot(surface_normal);
+		if dp < 0.0 {fresnel(dp, 1.0, index)} else {fresnel(dp, index, 1.0)}
+	    },
+	    MixFactor::Facing(falloff) => (1.0-incident.dot(surface_normal).abs()).powf(falloff),
+	};
+	factor.max(0.0).min(1.0)
+    }
+}
+
+pub struct ShadeMix { // linear interpolation between two nodes, always energy conserving
+    factor: MixFactor,
+    first: Box<Node>,
+    second: Box<Node>
+}
+
+impl ShadeMix {
+    pub fn new(factor: MixFactor, first: Node, second: Node) -> Self {
+	ShadeMix{factor: factor, first: Box::new(first), second: Box::new(second)}
+    }
+    pub fn shade_mix(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, footprint: Footprint, n_th: i32) -> Color {
+	let f = self.factor.get_factor(location, surface_normal, incident, obj, footprint);
+	let mut mix = consts::BLACK;
+	if f < 1.0 { // skip tracing branches that don't contribute
+	    mix += self.first.resolve(scene, location, surface_normal, incident, obj, footprint, n_th)*(1.0-f);
+	}
+	if f > 0.0 {
+	    mix += self.second.resolve(scene, location, surface_normal, incident, obj, footprint, n_th)*f;
+	}
+	mix
+    }
+}
+
+pub struct ShadeLayer { // plain sum of nodes, scaled by strength
+    strength: f64,
+    nodes: Vec<Node>
+}
 
-        (reflection_color+refraction_color)*self.strength*surface_color
+impl ShadeLayer {
+    pub fn new(strength: f64, nodes: Vec<Node>) -> Self {
+	ShadeLayer{strength: strength, nodes: nodes}
+    }
+    pub fn shade_layer(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, footprint: Footprint, n_th: i32) -> Color {
+	let mut mix = consts::BLACK;
+	for node in self.nodes.iter() {
+	    mix += node.resolve(scene, location, surface_normal, incident, obj, footprint, n_th);
+	}
+	mix*self.strength
     }
 }
 
 pub enum Node {
     Diffuse(ShadeDiffuse),
     Reflect(ShadeReflect),
-    Refract(ShadeRefract)
+    Refract(ShadeRefract),
+    AmbientOcclusion(ShadeAmbientOcclusion),
+    Toon(ShadeToon),
+    Mix(ShadeMix),
+    Layer(ShadeLayer)
 }
 
 impl Node {
-    pub fn resolve(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, n_th: i32) -> Color {
+    pub fn transmission(&self, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject) -> f64 {
+	// how much light the node lets straight through, for shadow rays
+	match *self {
+            Node::Refract(ref n) => n.strength.get(obj, &location, Footprint::POINT),
+            Node::Mix(ref n) => {
+		let f = n.factor.get_factor(location, surface_normal, incident, obj, Footprint::POINT);
+		n.first.transmission(location, surface_normal, incident, obj)*(1.0-f)
+		    + n.second.transmission(location, surface_normal, incident, obj)*f
+	    },
+            Node::Layer(ref n) => n.nodes.iter().map(|node| node.transmission(location, surface_normal, incident, obj)).sum::<f64>()*n.strength,
+            _ => 0.0
+        }
+    }
+    pub fn reflection(&self, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject) -> f64 {
+	// share of light mirrored by the node, for photons
+	match *self {
+            Node::Reflect(ref n) => n.strength.get(obj, &location, Footprint::POINT),
+            Node::Mix(ref n) => {
+		let f = n.factor.get_factor(location, surface_normal, incident, obj, Footprint::POINT);
+		n.first.reflection(location, surface_normal, incident, obj)*(1.0-f)
+		    + n.second.reflection(location, surface_normal, incident, obj)*f
+	    },
+            Node::Layer(ref n) => n.nodes.iter().map(|node| node.reflection(location, surface_normal, incident, obj)).sum::<f64>()*n.strength,
+            _ => 0.0
+        }
+    }
+    pub fn refraction_index(&self, location: Point3<f64>, obj: &SceneObject) -> Option<f64> {
+	match *self {
+            Node::Refract(ref n) => Some(n.index.get(obj, &location, Footprint::POINT)),
+            Node::Mix(ref n) => n.first.refraction_index(location, obj).or_else(|| n.second.refraction_index(location, obj)),
+            Node::Layer(ref n) => n.nodes.iter().filter_map(|node| node.refraction_index(location, obj)).next(),
+            _ => None
+        }
+    }
+    pub fn is_diffuse(&self) -> bool { // receives caustics
 	match *self {
-            Node::Diffuse(ref n) => n.shade_diffuse(scene, location, surface_normal, obj),
-            Node::Reflect(ref n) => n.shade_reflect(scene, location, incident, surface_normal, obj, n_th),
-            Node::Refract(ref n) => n.shade_refract(scene, location, incident, surface_normal, obj, n_th),
+            Node::Diffuse(_) => true,
+            Node::Mix(ref n) => n.first.is_diffuse() || n.second.is_diffuse(),
+            Node::Layer(ref n) => n.nodes.iter().any(|node| node.is_diffuse()),
+            _ => false
         }
     }
-    pub fn get_strength(&self) -> f64 {
+    pub fn is_specular(&self) -> bool { // casts caustics
 	match *self {
-            Node::Diffuse(ref n) => n.strength,
-            Node::Reflect(ref n) => n.strength,
-            Node::Refract(ref n) => n.strength
+            Node::Reflect(_) | Node::Refract(_) => true,
+            Node::Mix(ref n) => n.first.is_specular() || n.second.is_specular(),
+            Node::Layer(ref n) => n.nodes.iter().any(|node| node.is_specular()),
+            _ => false
         }
     }
-    pub fn set_strength(&mut self, new_strength: f64){
+    pub fn resolve(&self, scene: &Scene, location: Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>, obj: &SceneObject, footprint: Footprint, n_th: i32) -> Color {
 	match *self {
-            Node::Diffuse(ref mut n) => n.strength = new_strength,
-            Node::Reflect(ref mut n) => n.strength = new_strength,
-            Node::Refract(ref mut n) => n.strength = new_strength
+            Node::Diffuse(ref n) => n.shade_diffuse(scene, location, surface_normal, obj, footprint),
+            Node::Reflect(ref n) => n.shade_reflect(scene, location, incident, surface_normal, obj, footprint, n_th),
+            Node::Refract(ref n) => n.shade_refract(scene, location, incident, surface_normal, obj, footprint, n_th),
+            Node::AmbientOcclusion(ref n) => n.shade_ambient_occlusion(scene, location, surface_normal, incident, obj, footprint),
+            Node::Toon(ref n) => n.shade_toon(scene, location, surface_normal, obj, footprint),
+            Node::Mix(ref n) => n.shade_mix(scene, location, surface_normal, incident, obj, footprint, n_th),
+            Node::Layer(ref n) => n.shade_layer(scene, location, surface_normal, incident, obj, footprint, n_th),
         }
     }
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    Cutout(f64), // fully transparent below this opacity, opaque otherwise
+    Blend        // see-through in proportion, shadows are cut out at half opacity
+}
+
 pub struct Material {
+    pub alpha_mode: AlphaMode,
+    pub opacity: Param, // multiplied with the texture's alpha channel
     pub texture: Option<Texture>,
-    pub albedo: f64,
-    pub nodes: Vec<Node>
+    pub albedo: Param,
+    pub nodes: Vec<Node>,
+    pub normal_map: Option<ImageMap>, // tangent space, red along u, green along v, blue out
+    pub bump_map: Option<ImageMap>,   // height taken from brightness
+    pub bump_strength: f64,
+    pub absorption: Color // per unit distance travelled inside, black for clear
 }
 
 
 impl Material {
-    pub fn new(texture: Option<Texture>, albedo: f64, mut nodelist: Vec<Node>) -> Self {
-	let mut magnitude: f64 = 0.0; // normalize nodes
-	for node in nodelist.iter() {
-	    magnitude += node.get_strength().powi(2);
+    pub fn new(texture: Option<Texture>, albedo: impl Into<Param>, nodelist: Vec<Node>) -> Self {
+	// nodes are summed as-is, use Node::Mix to keep the total energy in check
+	Material{alpha_mode: AlphaMode::Opaque, opacity: Param::Constant(1.0), texture: texture, albedo: albedo.into(), nodes: nodelist,
+		 normal_map: None, bump_map: None, bump_strength: 1.0, absorption: consts::BLACK}
+    }
+    pub fn attenuation(&self, distance: f64) -> Color {
+	// Beer-Lambert falloff over a path of `distance` inside the object
+	let (r, g, b) = self.absorption.channels();
+	Color::new((-r*distance).exp(), (-g*distance).exp(), (-b*distance).exp())
+    }
+    pub fn perturb_normal(&self, normal: Vector3<f64>, tangent: Vector3<f64>, bitangent: Vector3<f64>, x: f64, y: f64) -> Vector3<f64> {
+	// tangent and bitangent follow increasing x and y texture coordinates
+	let mut perturbed = normal;
+	if let Some(ref map) = self.normal_map {
+	    let (tangent, bitangent) = map.transform.rotate_frame(tangent, bitangent);
+	    let (r, g, b) = map.get_color(x, y).channels();
+	    perturbed = (tangent*(2.0*r-1.0) + bitangent*(2.0*g-1.0) + normal*(2.0*b-1.0)).normalize();
 	}
-	magnitude = magnitude.sqrt();
-	for node in nodelist.iter_mut() {
-	    node.set_strength(node.get_strength()/magnitude);
+	if let Some(ref map) = self.bump_map {
+	    let (du, dv) = map.texel_size();
+	    let height = map.get_color(x, y).luminance();
+	    let slope_u = map.get_color(x+du, y).luminance()-height;
+	    let slope_v = map.get_color(x, y+dv).luminance()-height;
+	    perturbed = (perturbed - (tangent*slope_u + bitangent*slope_v)*self.bump_strength).normalize();
+	}
+	perturbed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayKind { // what a traced ray is looking for, checked against Visibility
+    Camera,
+    Reflection,
+    Refraction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Visibility {
+    pub casts_shadows: bool,
+    pub receives_shadows: bool,
+    pub camera: bool,
+    pub reflections: bool,
+    pub refractions: bool
+}
+
+impl Visibility {
+    pub const ALL: Visibility = Visibility{casts_shadows: true, receives_shadows: true,
+					   camera: true, reflections: true, refractions: true};
+    pub fn sees(&self, kind: RayKind) -> bool {
+	match kind {
+	    RayKind::Camera => self.camera,
+	    RayKind::Reflection => self.reflections,
+	    RayKind::Refraction => self.refractions
 	}
-	Material{texture: texture, albedo: albedo, nodes: nodelist}
     }
 }
 
 pub struct Sphere {
     origin: Point3<f64>,
     radius: f64,
-    pub material: Material
+    pub material: Material,
+    pub visibility: Visibility
 }
 
 
@@ -205,7 +565,7 @@ impl Sphere {
 	origin: Point3<f64>,
 	radius: f64,
 	material: Material) -> Self {
-	Self{origin: origin, radius: radius, material: material}
+	Self{origin: origin, radius: radius, material: material, visibility: Visibility::ALL}
     }                                  // distance --v  v--location  v-- surface normal
     pub fn intersects(&self, ray: &Ray) -> Option<(f64, Point3<f64>, Vector3<f64>)> {
 	let ray_to_sphere = self.origin - ray.origin;
@@ -242,35 +602,59 @@ impl Sphere {
 	let theta = (v[2]/self.radius).acos();
 	(if phi >= 0.0 {phi} else {2.0*std::f64::consts::PI+phi},if theta >= 0.0 {theta} else {2.0*std::f64::consts::PI+theta})
     }
+    fn get_uv(&self, location: &Point3<f64>) -> (f64, f64) {
+	// texture coordinates shared by every kind of texture, so one UvTransform places them all alike
+	let (phi, theta) = self.get_texture_coords(location);
+	(phi/std::f64::consts::PI, theta/std::f64::consts::PI)
+    }
+    fn get_tangents(&self, location: &Point3<f64>, surface_normal: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+	// directions of increasing phi and theta
+	let v = location-self.origin;
+	let around = Vector3{x: -v.y, y: v.x, z: 0.0};
+	let tangent = if around.magnitude2() > 1e-12 {around.normalize()} else {sampling::orthonormal_basis(surface_normal).0};
+	(tangent, tangent.cross(surface_normal))
+    }
+    pub fn get_surface_normal(&self, location: &Point3<f64>, surface_normal: Vector3<f64>) -> Vector3<f64> {
+	if self.material.normal_map.is_none() && self.material.bump_map.is_none() {
+	    return surface_normal;
+	}
+	let (tangent, bitangent) = self.get_tangents(location, surface_normal);
+	let (x, y) = self.get_uv(location);
+	self.material.perturb_normal(surface_normal, tangent, bitangent, x, y)
+    }
+    fn project_footprint(&self, location: &Point3<f64>, footprint: Footprint) -> ((f64, f64), (f64, f64)) {
+	// footprint axes as changes in get_uv, from the derivatives of phi and theta
+	let v = location-self.origin;
+	let rho2 = (v.x*v.x+v.y*v.y).max(1e-12);
+	let sin_theta = (1.0-(v.z/self.radius).powi(2)).max(1e-6).sqrt();
+	let pi = std::f64::consts::PI;
+	let project = |d: Vector3<f64>| ((v.x*d.y-v.y*d.x)/(rho2*pi), -d.z/(self.radius*sin_theta*pi));
+	(project(footprint.minor), project(footprint.major))
+    }
     
-    pub fn get_texture_color(&self, location: &Point3<f64>) -> Color {
-	match &self.material.texture {
+    pub fn get_texture_color(&self, location: &Point3<f64>, footprint: Footprint) -> Color {
+	self.sample_texture(self.material.texture.as_ref(), location, footprint)
+    }
+    pub fn get_texture_alpha(&self, location: &Point3<f64>, footprint: Footprint) -> f64 {
+	match self.material.texture {
+	    Some(Texture::ImageMap(ref image)) if image.has_alpha() => {
+		let (x, y) = self.get_uv(location);
+		let (minor, major) = self.project_footprint(location, footprint);
+		image.get_alpha_footprint(x, y, minor, major)
+	    },
+	    _ => 1.0
+	}
+    }
+    pub fn sample_texture(&self, texture: Option<&Texture>, location: &Point3<f64>, footprint: Footprint) -> Color {
+	match texture {
 	    Some(Texture::Color(color)) => *color,
-	    Some(Texture::ImageMap(ImageMap{pixvec, scale})) => {
-		let (mut x, mut y) = self.get_texture_coords(location);
-		x /= std::f64::consts::PI;
-		y /= std::f64::consts::PI;
-		if pixvec.width > pixvec.height {
-		    x %= scale;
-		    y %= scale*(pixvec.height as f64)/(pixvec.width as f64);
-		    if x < 0.0 {
-			x += scale;
-		    }
-		    if y < 0.0 {
-			y += scale*(pixvec.height as f64)/(pixvec.width as f64);
-		    }
-		    pixvec[(y*(pixvec.height as f64)/(scale*(pixvec.height as f64)/(pixvec.width as f64))) as usize][(x*(pixvec.width as f64)/scale) as usize]
-		} else {
-		    x %= scale*(pixvec.width as f64)/(pixvec.height as f64);
-		    y %= scale;
-		    if x < 0.0 {
-			x += scale*(pixvec.width as f64)/(pixvec.height as f64);
-		    }
-		    if y < 0.0 {
-			y += scale;
-		    }
-		    pixvec[(y*(pixvec.height as f64)/scale) as usize][(x*(pixvec.width as f64)/(scale*(pixvec.width as f64)/(pixvec.height as f64))) as usize]
-		}
+	    Some(Texture::ImageMap(image)) => {
+		let (x, y) = self.get_uv(location);
+		let (minor, major) = self.project_footprint(location, footprint);
+		image.get_color_footprint(x, y, minor, major)
+	    },
+	    Some(Texture::Procedural(procedural)) => {
+		procedural.get_color(location-self.origin, self.get_uv(location))
 	    },
 	    None => {
 		let (mut phi, theta) = self.get_texture_coords(location);
@@ -289,7 +673,8 @@ impl Sphere {
 pub struct Plane {
     pub origin: Point3<f64>,
     pub normal: Vector3<f64>,
-    pub material: Material
+    pub material: Material,
+    pub visibility: Visibility
 }
 
 
@@ -298,7 +683,7 @@ impl Plane {
     pub fn new(origin: Point3<f64>,
 	       normal: Vector3<f64>,
 	       material: Material) -> Self {
-	Self{origin: origin, normal: normal.normalize(), material: material}
+	Self{origin: origin, normal: normal.normalize(), material: material, visibility: Visibility::ALL}
     }                                  // distance --v  v--location  v-- surface normal
     pub fn intersects(&self, ray: &Ray) -> Option<(f64, Point3<f64>, Vector3<f64>)> {
         let proj = self.normal.dot(ray.direction);
@@ -317,8 +702,7 @@ impl Plane {
             None
 	}
     }
-    fn get_texture_coords(&self, location: &Point3<f64>) -> (f64, f64) {
-	let v = location-self.origin;
+    fn get_texture_axes(&self) -> (Vector3<f64>, Vector3<f64>) {
 	let mut x_axis = self.normal.cross(Vector3{
 	    x: 0.0,
 	    y: 0.0,
@@ -332,35 +716,49 @@ impl Plane {
 	    });
 	}
 	let y_axis = self.normal.cross(x_axis);
+	(x_axis, y_axis)
+    }
+    fn get_texture_coords(&self, location: &Point3<f64>) -> (f64, f64) {
+	let v = location-self.origin;
+	let (x_axis, y_axis) = self.get_texture_axes();
 	(v.dot(x_axis), v.dot(y_axis))
     }
+    pub fn get_surface_normal(&self, location: &Point3<f64>, surface_normal: Vector3<f64>) -> Vector3<f64> {
+	if self.material.normal_map.is_none() && self.material.bump_map.is_none() {
+	    return surface_normal;
+	}
+	let (x_axis, y_axis) = self.get_texture_axes(); // directions of increasing x and y
+	let (x, y) = self.get_texture_coords(location);
+	self.material.perturb_normal(surface_normal, x_axis.normalize(), y_axis.normalize(), x, y)
+    }
+    fn project_footprint(&self, footprint: Footprint) -> ((f64, f64), (f64, f64)) {
+	// texture coordinates are linear on a plane, so axes project like any other vector
+	(self.get_texture_coords(&(self.origin+footprint.minor)), self.get_texture_coords(&(self.origin+footprint.major)))
+    }
     
-    pub fn get_texture_color(&self, location: &Point3<f64>) -> Color {
-	match &self.material.texture {
+    pub fn get_texture_color(&self, location: &Point3<f64>, footprint: Footprint) -> Color {
+	self.sample_texture(self.material.texture.as_ref(), location, footprint)
+    }
+    pub fn get_texture_alpha(&self, location: &Point3<f64>, footprint: Footprint) -> f64 {
+	match self.material.texture {
+	    Some(Texture::ImageMap(ref image)) if image.has_alpha() => {
+		let (x, y) = self.get_texture_coords(location);
+		let (minor, major) = self.project_footprint(footprint);
+		image.get_alpha_footprint(x, y, minor, major)
+	    },
+	    _ => 1.0
+	}
+    }
+    pub fn sample_texture(&self, texture: Option<&Texture>, location: &Point3<f64>, footprint: Footprint) -> Color {
+	match texture {
 	    Some(Texture::Color(color)) => *color,
-	    Some(Texture::ImageMap(ImageMap{pixvec, scale})) => {
-		let (mut x, mut y) = self.get_texture_coords(location);
-		if pixvec.width > pixvec.height {
-		    x %= scale;
-		    y %= scale*(pixvec.height as f64)/(pixvec.width as f64);
-		    if x < 0.0 {
-			x += scale;
-		    }
-		    if y < 0.0 {
-			y += scale*(pixvec.height as f64)/(pixvec.width as f64);
-		    }
-		    pixvec[(y*(pixvec.height as f64)/(scale*(pixvec.height as f64)/(pixvec.width as f64))) as usize][(x*(pixvec.width as f64)/scale) as usize]
-		} else {
-		    x %= scale*(pixvec.width as f64)/(pixvec.height as f64);
-		    y %= scale;
-		    if x < 0.0 {
-			x += scale*(pixvec.width as f64)/(pixvec.height as f64);
-		    }
-		    if y < 0.0 {
-			y += scale;
-		    }
-		    pixvec[(y*(pixvec.height as f64)/scale) as usize][(x*(pixvec.width as f64)/(scale*(pixvec.width as f64)/(pixvec.height as f64))) as usize]
-		}
+	    Some(Texture::ImageMap(image)) => {
+		let (x, y) = self.get_texture_coords(location);
+		let (minor, major) = self.project_footprint(footprint);
+		image.get_color_footprint(x, y, minor, major)
+	    },
+	    Some(Texture::Procedural(procedural)) => {
+		procedural.get_color(location-self.origin, self.get_texture_coords(location))
 	    },
 	    None => {
 		let (mut x, mut y) = self.get_texture_coords(location);
@@ -381,105 +779,94 @@ impl Plane {
 }
 
 
-pub struct Sun {
-    pub direction: Vector3<f64>,
-    pub color: Color,
-    pub intensity: f64
-}
-
-
-impl Sun {
-    pub fn new(direction: Vector3<f64>,
-	       color: Color,
-	       intensity: f64) -> Self {
-	Sun{direction: direction.normalize(), color: color, intensity: intensity}
-    }
-}
-
-
-pub struct PointLight {
-    pub origin: Point3<f64>,
-    pub color: Color,
-    pub intensity: f64
-}
-
-
-impl PointLight {
-    pub fn new(position: Point3<f64>,
-	       color: Color,
-	       intensity: f64) -> Self {
-	PointLight{origin: position, color: color, intensity: intensity}
-    }
+pub enum SceneObject {
+    Sphere(Sphere),
+    Plane(Plane)
 }
 
 
-
-pub enum SceneLight {
-    Sun(Sun),
-    PointLight(PointLight)
-}
-
-impl SceneLight {
-    pub fn dist_to(&self, point: Point3<f64>) -> f64 {
+impl SceneObject {
+    pub fn get_material(&self) -> &Material {
 	match *self {
-            SceneLight::Sun(ref _s) => std::f64::MAX,
-            SceneLight::PointLight(ref p) => {
-		p.origin.distance(point)
-	    }
+            SceneObject::Sphere(ref s) => &s.material,
+            SceneObject::Plane(ref p) => &p.material,
         }
     }
-    pub fn get_apparent_intensity(&self, dp: f64, distance: f64) -> f64 {
+    pub fn get_visibility(&self) -> Visibility {
 	match *self {
-            SceneLight::Sun(ref s) => (
-		if dp < 0.0 {0.0}
-		else {dp*s.intensity}
-	    ),
-            SceneLight::PointLight(ref p) => {
-		if dp < 0.0 {0.0}
-		else {dp*p.intensity/(4.0*std::f64::consts::PI*distance.powi(2))}
-	    },
+            SceneObject::Sphere(ref s) => s.visibility,
+            SceneObject::Plane(ref p) => p.visibility,
         }
     }
-    pub fn get_direction(&self, point: Point3<f64>) -> Vector3<f64> {
-	match *self {
-            SceneLight::Sun(ref s) => -s.direction,
-            SceneLight::PointLight(ref p) => {
-		(p.origin-point).normalize()
-	    }
-        }
+    pub fn get_opacity(&self, location: &Point3<f64>, footprint: Footprint) -> f64 {
+	let material = self.get_material();
+	if material.alpha_mode == AlphaMode::Opaque {
+	    return 1.0;
+	}
+	let texture_alpha = match *self {
+            SceneObject::Sphere(ref s) => s.get_texture_alpha(location, footprint),
+            SceneObject::Plane(ref p) => p.get_texture_alpha(location, footprint),
+        };
+	texture_alpha*material.opacity.get(self, location, footprint)
+    }
+    pub fn blocks_light(&self, location: &Point3<f64>) -> bool {
+	match self.get_material().alpha_mode {
+	    AlphaMode::Opaque => true,
+	    AlphaMode::Cutout(threshold) => self.get_opacity(location, Footprint::POINT) >= threshold,
+	    AlphaMode::Blend => self.get_opacity(location, Footprint::POINT) >= 0.5
+	}
     }
-    pub fn get_color(&self) -> &Color {
+    pub fn get_transmission(&self, location: &Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>) -> Color {
+	// tint of light passing straight through the surface, black for opaque materials
+	let transmission: f64 = self.get_nodes().iter()
+	    .map(|node| node.transmission(*location, surface_normal, incident, self)).sum();
+	if transmission <= 0.0 {
+	    consts::BLACK
+	} else {
+	    self.get_texture_color(location, Footprint::POINT)*transmission.min(1.0)
+	}
+    }
+    pub fn get_reflection(&self, location: &Point3<f64>, surface_normal: Vector3<f64>, incident: Vector3<f64>) -> f64 {
+	self.get_nodes().iter().map(|node| node.reflection(*location, surface_normal, incident, self)).sum::<f64>().min(1.0)
+    }
+    pub fn get_refraction_index(&self, location: &Point3<f64>) -> Option<f64> {
+	self.get_nodes().iter().filter_map(|node| node.refraction_index(*location, self)).next()
+    }
+    pub fn bounding_sphere(&self) -> Option<(Point3<f64>, f64)> { // None when unbounded
 	match *self {
-            SceneLight::Sun(ref s) => &s.color,
-            SceneLight::PointLight(ref p) => &p.color
+            SceneObject::Sphere(ref s) => Some((s.origin, s.radius)),
+            SceneObject::Plane(ref _p) => None,
         }
     }
-}
-
-
-pub enum SceneObject {
-    Sphere(Sphere),
-    Plane(Plane)
-}
-
-
-impl SceneObject {
     pub fn get_nodes(&self) -> &Vec<Node> {
 	match *self {
             SceneObject::Sphere(ref s) => &s.material.nodes,
             SceneObject::Plane(ref p) => &p.material.nodes,
         }
     }
-    pub fn get_albedo(&self) -> f64 {
+    pub fn get_albedo(&self, location: &Point3<f64>, footprint: Footprint) -> f64 {
+	match *self {
+            SceneObject::Sphere(ref s) => s.material.albedo.get(self, location, footprint),
+            SceneObject::Plane(ref p) => p.material.albedo.get(self, location, footprint),
+        }
+    }
+    pub fn get_surface_normal(&self, location: &Point3<f64>, surface_normal: Vector3<f64>) -> Vector3<f64> {
+	// geometric normal with the material's normal and bump maps applied
+	match *self {
+            SceneObject::Sphere(ref s) => s.get_surface_normal(location, surface_normal),
+            SceneObject::Plane(ref p) => p.get_surface_normal(location, surface_normal),
+        }
+    }
+    pub fn get_texture_color(&self, location: &Point3<f64>, footprint: Footprint) -> Color {
 	match *self {
-            SceneObject::Sphere(ref s) => s.material.albedo,
-            SceneObject::Plane(ref p) => p.material.albedo,
+            SceneObject::Sphere(ref s) => s.get_texture_color(location, footprint),
+            SceneObject::Plane(ref p) => p.get_texture_color(location, footprint),
         }
     }
-    pub fn get_texture_color(&self, location: &Point3<f64>) -> Color {
+    pub fn sample_texture(&self, texture: Option<&Texture>, location: &Point3<f64>, footprint: Footprint) -> Color {
 	match *self {
-            SceneObject::Sphere(ref s) => s.get_texture_color(location),
-            SceneObject::Plane(ref p) => p.get_texture_color(location),
+            SceneObject::Sphere(ref s) => s.sample_texture(texture, location, footprint),
+            SceneObject::Plane(ref p) => p.sample_texture(texture, location, footprint),
         }
     }
     pub fn intersects(&self, ray: &Ray) -> Option<(f64, Point3<f64>, Vector3<f64>)> {
@@ -498,23 +885,61 @@ pub struct Ray {
 impl Ray {
     fn any_intersect(&self, scene: &Scene, target_distance: f64) -> bool {
 	//simply checks if there's an intersection before a target distance
-	let mut hit_something = false;
-	
-	for scene_object in scene.objects.iter() {
-	    if let Some((dist, _location, _normal)) = scene_object.intersects(self) {
-		if dist <= target_distance {
-		    hit_something = true;
+	for scene_object in scene.objects.iter().filter(|o| o.get_visibility().casts_shadows) {
+	    let mut ray = Ray{origin: self.origin, direction: self.direction};
+	    let mut travelled = 0.0;
+	    // keep going through transparent texels until something blocks or the target is reached
+	    while let Some((dist, location, _normal)) = scene_object.intersects(&ray) {
+		if travelled+dist > target_distance {
 		    break;
 		}
+		if scene_object.blocks_light(&location) {
+		    return true;
+		}
+		travelled += dist+PASS_BIAS;
+		ray.origin = location+self.direction*PASS_BIAS;
 	    }
 	}
-	hit_something
+	false
     }
-    fn closest_intersect<'a>(&self, scene: &'a Scene) -> Option<(f64, Point3<f64>, Vector3<f64>, &'a SceneObject)> {
+    fn transmittance(&self, scene: &Scene, target_distance: f64, caustics_from: Option<&SceneLight>) -> Color {
+	// light reaching target_distance along the ray, tinted by refractive objects on the way;
+	// with caustics_from, objects that light already sends photons through block it instead
+	let photon_casters = match (caustics_from, scene.caustics.as_ref()) {
+	    (Some(light), Some(caustics)) => caustics.casters_for(light),
+	    _ => &[]
+	};
+	let mut transmitted = consts::WHITE;
+	for (index, scene_object) in scene.objects.iter().enumerate().filter(|(_, o)| o.get_visibility().casts_shadows) {
+	    let mut ray = Ray{origin: self.origin, direction: self.direction};
+	    let mut travelled = 0.0;
+	    while let Some((dist, location, normal)) = scene_object.intersects(&ray) {
+		if travelled+dist > target_distance {
+		    break;
+		}
+		if scene_object.blocks_light(&location) {
+		    if photon_casters.contains(&index) {
+			return consts::BLACK;
+		    }
+		    transmitted = transmitted*scene_object.get_transmission(&location, normal, self.direction);
+		    if self.direction.dot(normal) > 0.0 { // leaving, so the last stretch was inside
+			transmitted = transmitted*scene_object.get_material().attenuation(dist);
+		    }
+		    if transmitted.luminance() <= 0.0 {
+			return consts::BLACK;
+		    }
+		}
+		travelled += dist+PASS_BIAS;
+		ray.origin = location+self.direction*PASS_BIAS;
+	    }
+	}
+	transmitted
+    }
+    pub fn closest_intersect<'a>(&self, scene: &'a Scene, kind: RayKind) -> Option<(f64, Point3<f64>, Vector3<f64>, &'a SceneObject)> {
 	// finds the closest intersection and returns an Option with the following in order:
 	// distance, location of intersection, surface normal of object at reflected point, reference to object (for color, etc.)
 	let mut intersection : Option<(f64, Point3<f64>, Vector3<f64>, &'a SceneObject)> = None;
-	for scene_object in scene.objects.iter() {
+	for scene_object in scene.objects.iter().filter(|o| o.get_visibility().sees(kind)) {
 	    if let Some((dist, location, normal)) = scene_object.intersects(self) {
 		if !intersection.is_some() || intersection.unwrap().0 > dist {
 		    intersection = Some((dist, location, normal, scene_object));
@@ -522,17 +947,69 @@ impl Ray {
 	    }
 	}
 	intersection
+    }
+    pub fn probe(&self, scene: &Scene) -> Option<(f64, Vector3<f64>, usize)> {
+	// like closest_intersect, but identifies the object by its index in the scene
+	let mut intersection : Option<(f64, Vector3<f64>, usize)> = None;
+	for (index, scene_object) in scene.objects.iter().enumerate().filter(|(_, o)| o.get_visibility().camera) {
+	    if let Some((dist, _location, normal)) = scene_object.intersects(self) {
+		if !intersection.is_some() || intersection.unwrap().0 > dist {
+		    intersection = Some((dist, normal, index));
+		}
+	    }
+	}
+	intersection
     } //                                                        v-- power @ pixel
     pub fn trace(&self, scene: &Scene, n_th: i32) -> Option<(Color, f64)> { // from direction of next
+	self.trace_cone(scene, n_th, 0.0, RayKind::Camera)
+    }
+    fn footprint(&self, scene: &Scene, cone: f64, dist: f64, surface_normal: Vector3<f64>) -> Footprint {
+	// ray cone: width grows by the camera's pixel spread and stretches along grazing surfaces
+	let width = cone + scene.camera.pixel_spread()*dist;
+	let cos = self.direction.dot(surface_normal);
+	let along = self.direction - surface_normal*cos; // ray direction flattened onto the surface
+	let major_dir = if along.magnitude2() > 1e-12 {along.normalize()} else {sampling::orthonormal_basis(surface_normal).0};
+	let minor_dir = surface_normal.cross(major_dir);
+	Footprint{minor: minor_dir*width, major: major_dir*(width/cos.abs().max(0.01))}
+    }
+    pub fn trace_cone(&self, scene: &Scene, n_th: i32, cone: f64, kind: RayKind) -> Option<(Color, f64)> {
+	// cone is the footprint width at the ray's origin, for texture filtering
 	let ret = 
-	    if let Some((_dist, location, surface_normal, obj)) = self.closest_intersect(scene) {
+	    if let Some((dist, location, surface_normal, obj)) = self.closest_intersect(scene, kind) {
+		let footprint = self.footprint(scene, cone, dist, surface_normal);
+		let (threshold, blend) = match obj.get_material().alpha_mode {
+		    AlphaMode::Opaque => (0.0, false),
+		    AlphaMode::Cutout(threshold) => (threshold, false),
+		    AlphaMode::Blend => (0.0, true)
+		};
+		let opacity = obj.get_opacity(&location, footprint);
+		let behind = || { // whatever the ray sees through this hit
+		    if n_th < RECURSION_DEPTH {
+			Ray{origin: location+self.direction*PASS_BIAS, direction: self.direction}
+			    .trace_cone(scene, n_th+1, cone+scene.camera.pixel_spread()*dist, kind)
+		    } else {
+			None
+		    }
+		};
+		if opacity < threshold || (blend && opacity <= 0.0) {
+		    return behind();
+		}
+		let inside = self.direction.dot(surface_normal) > 0.0;
+		let surface_normal = obj.get_surface_normal(&location, surface_normal);
 		let mut color_tally = consts::BLACK;
 		for node in obj.get_nodes() {
-		    color_tally = color_tally + node.resolve(scene, location, surface_normal, self.direction, obj, n_th+1);
+		    color_tally = color_tally + node.resolve(scene, location, surface_normal, self.direction, obj, footprint, n_th+1);
+		}
+		if inside { // the ray crossed the object's interior to get here
+		    color_tally = color_tally*obj.get_material().attenuation(dist);
+		}
+		if blend && opacity < 1.0 {
+		    let through = behind().map(|(color, _power)| color).unwrap_or(consts::BLACK);
+		    color_tally = color_tally*opacity + through*(1.0-opacity);
 		}
 		Some((color_tally, 0.0))
 	    } else {
-		None
+		Some((scene.background.get_color(self.direction), 0.0))
 	    };
 	ret
     }
@@ -543,5 +1020,17 @@ pub struct Scene {
     pub camera: Camera,
     pub objects: Vec<SceneObject>,
     pub lights: Vec<SceneLight>,
+    pub outline: Option<Outline>,
+    pub background: Background,
+    pub light_sampling: LightSampling,
+    pub caustics: Option<PhotonMap>, // built from the rest of the scene with PhotonMap::new
     pub white_balance: f64
 }
+
+
+impl Scene {
+    pub fn object_index(&self, obj: &SceneObject) -> usize {
+	// position in `objects`, as used by light linking and outlines
+	self.objects.iter().position(|o| std::ptr::eq(o, obj)).unwrap_or(std::usize::MAX)
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use cgmath::Vector3;
+use crate::cgmath::InnerSpace;
+use crate::color::*;
+use crate::pixvec::*;
+use crate::procedural::Procedural;
+use crate::radiance;
+use crate::openexr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Footprint { // ellipse a camera pixel covers around a hit, as world space axes
+    pub minor: Vector3<f64>,
+    pub major: Vector3<f64>
+}
+
+impl Footprint {
+    pub const POINT: Footprint = Footprint{minor: Vector3{x: 0.0, y: 0.0, z: 0.0},
+					   major: Vector3{x: 0.0, y: 0.0, z: 0.0}};
+    pub fn width(&self) -> f64 {
+	self.minor.magnitude()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Bicubic // catmull-rom
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MipMode {
+    None,             // always sample the full resolution image
+    Trilinear,        // blend the two levels matching the footprint's long axis
+    Anisotropic(u32)  // several probes along the long axis, up to this many
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    Mirror,
+    Clamp
+}
+
+impl Wrap {
+    fn apply(&self, i: i64, n: usize) -> usize {
+	let n = n as i64;
+	let wrapped = match *self {
+	    Wrap::Repeat => i.rem_euclid(n),
+	    Wrap::Mirror => {
+		let m = i.rem_euclid(2*n);
+		if m >= n {2*n-1-m} else {m}
+	    },
+	    Wrap::Clamp => i.max(0).min(n-1)
+	};
+	wrapped as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UvTransform { // placement of a texture, applied the same way on every primitive
+    pub offset: (f64, f64),
+    pub rotation: f64,      // radians, counter-clockwise around the texture origin
+    pub tiling: (f64, f64)  // repeats per texture unit along u and v
+}
+
+impl UvTransform {
+    pub const IDENTITY: UvTransform = UvTransform{offset: (0.0, 0.0), rotation: 0.0, tiling: (1.0, 1.0)};
+    pub fn new(offset: (f64, f64), rotation: f64, tiling: (f64, f64)) -> Self {
+	UvTransform{offset: offset, rotation: rotation, tiling: tiling}
+    }
+    pub fn apply_vector(&self, x: f64, y: f64) -> (f64, f64) { // without the offset
+	let (sin, cos) = self.rotation.sin_cos();
+	((x*cos - y*sin)*self.tiling.0, (x*sin + y*cos)*self.tiling.1)
+    }
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+	let (u, v) = self.apply_vector(x, y);
+	(u+self.offset.0, v+self.offset.1)
+    }
+    pub fn rotate_frame(&self, tangent: Vector3<f64>, bitangent: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+	// surface directions of increasing u and v after rotation
+	let (sin, cos) = self.rotation.sin_cos();
+	(tangent*cos - bitangent*sin, tangent*sin + bitangent*cos)
+    }
+}
+
+pub struct ImageData { // a decoded image with its mip pyramids, shared between image maps
+    pub pixvec: Pixvec,
+    mips: Vec<Pixvec>,       // successively halved copies of pixvec
+    alpha: Option<Pixvec>,   // pixvec's alpha channel as grey, if it has one
+    alpha_mips: Vec<Pixvec>
+}
+
+impl ImageData {
+    pub fn new(pixvec: Pixvec) -> Self {
+	let mips = ImageData::build_mips(&pixvec);
+	let alpha = pixvec.alpha_to_pixvec();
+	let alpha_mips = match alpha {
+	    Some(ref a) => ImageData::build_mips(a),
+	    None => Vec::new()
+	};
+	ImageData{pixvec: pixvec, mips: mips, alpha: alpha, alpha_mips: alpha_mips}
+    }
+    pub fn new_from_file(uri: String, srgb: bool) -> Self {
+	// .hdr and .exr files are already linear and keep values above 1.0
+	let lower = uri.to_lowercase();
+	if lower.ends_with(".hdr") {
+	    ImageData::new(radiance::read_file(&uri).unwrap())
+	} else if lower.ends_with(".exr") {
+	    ImageData::new(openexr::read_file(&uri).unwrap())
+	} else {
+	    ImageData::new(Pixvec::from_pixbuf(&gdk_pixbuf::Pixbuf::new_from_file(uri).unwrap(), srgb))
+	}
+    }
+    pub fn memory_usage(&self) -> usize { // bytes
+	let mut total = self.pixvec.memory_usage();
+	for level in self.mips.iter().chain(self.alpha.iter()).chain(self.alpha_mips.iter()) {
+	    total += level.memory_usage();
+	}
+	total
+    }
+    fn build_mips(base: &Pixvec) -> Vec<Pixvec> {
+	let mut mips: Vec<Pixvec> = Vec::new();
+	let (mut width, mut height) = (base.width, base.height);
+	while width > 1 || height > 1 {
+	    let source = mips.last().unwrap_or(base);
+	    width = (width/2).max(1);
+	    height = (height/2).max(1);
+	    let mut level = Pixvec::new(width, height);
+	    for i in 0..height {
+		for j in 0..width { // box filter over the 2x2 block, clamped at odd edges
+		    let (i0, j0) = ((2*i).min(source.height-1), (2*j).min(source.width-1));
+		    let (i1, j1) = ((2*i+1).min(source.height-1), (2*j+1).min(source.width-1));
+		    level[i][j] = (source[i0][j0] + source[i0][j1] + source[i1][j0] + source[i1][j1])*0.25;
+		}
+	    }
+	    mips.push(level);
+	}
+	mips
+    }
+}
+
+
+pub struct TextureCache { // decodes each file once and hands out shared references
+    images: HashMap<(String, bool), Arc<ImageData>>
+}
+
+impl TextureCache {
+    pub fn new() -> Self {
+	TextureCache{images: HashMap::new()}
+    }
+    pub fn load(&mut self, uri: &str, srgb: bool) -> Arc<ImageData> {
+	self.images.entry((uri.to_string(), srgb))
+	    .or_insert_with(|| Arc::new(ImageData::new_from_file(uri.to_string(), srgb)))
+	    .clone()
+    }
+    pub fn image_map(&mut self, uri: &str, scale: f64) -> ImageMap {
+	ImageMap::new(self.load(uri, true), scale)
+    }
+    pub fn image_map_linear(&mut self, uri: &str, scale: f64) -> ImageMap {
+	ImageMap::new(self.load(uri, false), scale)
+    }
+    pub fn len(&self) -> usize {
+	self.images.len()
+    }
+    pub fn memory_usage(&self) -> usize { // bytes
+	self.images.values().map(|image| image.memory_usage()).sum()
+    }
+}
+
+
+pub struct ImageMap {
+    pub data: Arc<ImageData>,
+    pub scale: f64,
+    pub filter: Filter,
+    pub wrap: Wrap,
+    pub mip: MipMode,
+    pub transform: UvTransform
+}
+
+
+impl ImageMap {
+    pub fn new(data: Arc<ImageData>, scale: f64) -> Self {
+	ImageMap{data: data, scale: scale, filter: Filter::Bilinear, wrap: Wrap::Repeat, mip: MipMode::Trilinear, transform: UvTransform::IDENTITY}
+    }
+    pub fn new_from_file(uri: String, scale: f64) -> Self {
+	ImageMap::new_from_file_filtered(uri, scale, Filter::Bilinear, Wrap::Repeat)
+    }
+    pub fn new_from_file_filtered(uri: String, scale: f64, filter: Filter, wrap: Wrap) -> Self {
+	let mut image = ImageMap::new(Arc::new(ImageData::new_from_file(uri, true)), scale);
+	image.filter = filter;
+	image.wrap = wrap;
+	image
+    }
+    pub fn new_from_file_linear(uri: String, scale: f64) -> Self {
+	// for data maps like normals and heights, which aren't stored as srgb
+	ImageMap::new(Arc::new(ImageData::new_from_file(uri, false)), scale)
+    }
+    pub fn new_from_pixvec(pixvec: Pixvec, scale: f64, filter: Filter, wrap: Wrap) -> Self {
+	let mut image = ImageMap::new(Arc::new(ImageData::new(pixvec)), scale);
+	image.filter = filter;
+	image.wrap = wrap;
+	image
+    }
+    pub fn has_alpha(&self) -> bool {
+	self.data.alpha.is_some()
+    }
+    fn level<'a>(base: &'a Pixvec, mips: &'a [Pixvec], n: usize) -> &'a Pixvec {
+	if n == 0 {base} else {&mips[n-1]}
+    }
+    fn texel(&self, image: &Pixvec, x: i64, y: i64) -> Color {
+	image[self.wrap.apply(y, image.height)][self.wrap.apply(x, image.width)]
+    }
+    fn catmull_rom(t: f64) -> [f64; 4] { // weights for the four texels around t
+	let t2 = t*t;
+	let t3 = t2*t;
+	[0.5*(-t3 + 2.0*t2 - t),
+	 0.5*(3.0*t3 - 5.0*t2 + 2.0),
+	 0.5*(-3.0*t3 + 4.0*t2 + t),
+	 0.5*(t3 - t2)]
+    }
+    fn filtered(&self, image: &Pixvec, u: f64, v: f64) -> Color {
+	// u and v are in texels of the given image
+	match self.filter {
+	    Filter::Nearest => self.texel(image, u.floor() as i64, v.floor() as i64),
+	    Filter::Bilinear => {
+		let (tx, ty) = (u-0.5, v-0.5);
+		let (x0, y0) = (tx.floor(), ty.floor());
+		let (fx, fy) = (tx-x0, ty-y0);
+		let (x0, y0) = (x0 as i64, y0 as i64);
+		(self.texel(image, x0, y0)*(1.0-fx) + self.texel(image, x0+1, y0)*fx)*(1.0-fy)
+		    + (self.texel(image, x0, y0+1)*(1.0-fx) + self.texel(image, x0+1, y0+1)*fx)*fy
+	    },
+	    Filter::Bicubic => {
+		let (tx, ty) = (u-0.5, v-0.5);
+		let (x0, y0) = (tx.floor(), ty.floor());
+		let (wx, wy) = (ImageMap::catmull_rom(tx-x0), ImageMap::catmull_rom(ty-y0));
+		let (x0, y0) = (x0 as i64, y0 as i64);
+		let mut mix = consts::BLACK;
+		for j in 0..4 {
+		    let mut row = consts::BLACK;
+		    for i in 0..4 {
+			row += self.texel(image, x0+i as i64-1, y0+j as i64-1)*wx[i];
+		    }
+		    mix += row*wy[j];
+		}
+		mix
+	    }
+	}
+    }
+    fn sample_lod(&self, base: &Pixvec, mips: &[Pixvec], lod: f64, u: f64, v: f64) -> Color {
+	// blends the two mip levels around lod, u and v are in full resolution texels
+	let lod = lod.max(0.0).min(mips.len() as f64);
+	let l0 = lod.floor() as usize;
+	let l1 = (l0+1).min(mips.len());
+	let f = lod-(l0 as f64);
+	let s0 = 1.0/((1 << l0) as f64);
+	let c0 = self.filtered(ImageMap::level(base, mips, l0), u*s0, v*s0);
+	if f > 0.0 && l1 != l0 {
+	    let s1 = 1.0/((1 << l1) as f64);
+	    c0*(1.0-f) + self.filtered(ImageMap::level(base, mips, l1), u*s1, v*s1)*f
+	} else {
+	    c0
+	}
+    }
+    fn texels_per_unit(&self) -> (f64, f64) {
+	// one image spans `scale` texture units along its longer side
+	let (width, height) = (self.data.pixvec.width as f64, self.data.pixvec.height as f64);
+	if width > height {
+	    (width/self.scale, height/(self.scale*height/width))
+	} else {
+	    (width/(self.scale*width/height), height/self.scale)
+	}
+    }
+    pub fn texel_size(&self) -> (f64, f64) { // in untransformed texture units, ignoring rotation
+	let (sx, sy) = self.texels_per_unit();
+	(1.0/(sx*self.transform.tiling.0.abs()), 1.0/(sy*self.transform.tiling.1.abs()))
+    }
+    pub fn get_color(&self, x: f64, y: f64) -> Color {
+	let (sx, sy) = self.texels_per_unit();
+	let (x, y) = self.transform.apply(x, y);
+	self.filtered(&self.data.pixvec, x*sx, y*sy)
+    }
+    pub fn get_color_uv(&self, u: f64, v: f64) -> Color {
+	// u and v in [0, 1] span the whole image, ignoring scale and transform
+	let pixvec = &self.data.pixvec;
+	let half_texel = 0.5/(pixvec.height as f64);
+	let v = v.max(half_texel).min(1.0-half_texel); // don't blend across the poles
+	self.filtered(pixvec, u*(pixvec.width as f64), v*(pixvec.height as f64))
+    }
+    pub fn get_color_footprint(&self, x: f64, y: f64, minor: (f64, f64), major: (f64, f64)) -> Color {
+	// minor and major are the footprint axes in texture units
+	self.lookup(&self.data.pixvec, &self.data.mips, x, y, minor, major)
+    }
+    pub fn get_alpha(&self, x: f64, y: f64) -> f64 {
+	self.get_alpha_footprint(x, y, (0.0, 0.0), (0.0, 0.0))
+    }
+    pub fn get_alpha_footprint(&self, x: f64, y: f64, minor: (f64, f64), major: (f64, f64)) -> f64 {
+	match self.data.alpha {
+	    Some(ref alpha) => self.lookup(alpha, &self.data.alpha_mips, x, y, minor, major).channels().0,
+	    None => 1.0
+	}
+    }
+    fn lookup(&self, base: &Pixvec, mips: &[Pixvec], x: f64, y: f64, minor: (f64, f64), major: (f64, f64)) -> Color {
+	let (sx, sy) = self.texels_per_unit();
+	let (x, y) = self.transform.apply(x, y);
+	let minor = self.transform.apply_vector(minor.0, minor.1);
+	let major = self.transform.apply_vector(major.0, major.1);
+	let (u, v) = (x*sx, y*sy);
+	let minor_len = (minor.0*sx).hypot(minor.1*sy);
+	let major_len = (major.0*sx).hypot(major.1*sy).max(minor_len);
+	if major_len <= 1.0 {
+	    return self.filtered(base, u, v); // magnified, mips can't help
+	}
+	match self.mip {
+	    MipMode::None => self.filtered(base, u, v),
+	    MipMode::Trilinear => self.sample_lod(base, mips, major_len.log2(), u, v),
+	    MipMode::Anisotropic(max_probes) => {
+		let max_probes = max_probes.max(1) as f64;
+		let probes = (major_len/minor_len.max(1e-9)).ceil().max(1.0).min(max_probes);
+		let lod = (major_len/probes).max(minor_len).max(1.0).log2();
+		let mut mix = consts::BLACK;
+		for i in 0..(probes as usize) {
+		    let t = (i as f64+0.5)/probes-0.5;
+		    mix += self.sample_lod(base, mips, lod, u+major.0*sx*t, v+major.1*sy*t);
+		}
+		mix/probes
+	    }
+	}
+    }
+}
+
+
+pub enum Texture {
+    Color(Color),
+    ImageMap(ImageMap),
+    Procedural(Procedural)
+}